@@ -0,0 +1,178 @@
+//! Arret gracieux et supervision des taches non critiques.
+//!
+//! Sur reception de SIGTERM (ou ctrl-c), les nouvelles commandes et requetes sont retenues sans etre
+//! acquittees : RabbitMQ les remet dans la Q a la fermeture de la connexion (autre instance ou
+//! redemarrage). Les traitements en cours (incluant leur session Mongo) se terminent toujours avant
+//! l'arret des taches. Le delai d'arret du conteneur (e.g. stop_grace_period) doit etre plus long que
+//! la commande la plus longue, sinon SIGKILL interrompt la session et sa transaction n'est pas commitee.
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use millegrilles_common_rust::futures::FutureExt;
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::signal::unix::{signal, SignalKind};
+
+use crate::domain_manager::DocumentsDomainManager;
+
+/// Intervalle entre les avertissements pendant l'attente des traitements en cours.
+pub const CONST_DELAI_ARRET: Duration = Duration::from_secs(8);
+
+const CONST_BACKOFF_INITIAL: Duration = Duration::from_secs(5);
+const CONST_BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// Une tache qui a fonctionne au moins ce delai redemarre avec le backoff initial.
+const CONST_DUREE_STABLE: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Default)]
+pub struct EtatArret {
+    demande: AtomicBool,
+    en_cours: AtomicUsize,
+}
+
+/// Traitement en cours, retire du compteur lorsqu'il est droppe.
+pub struct TraitementEnCours<'a> {
+    etat: &'a EtatArret,
+}
+
+impl Drop for TraitementEnCours<'_> {
+    fn drop(&mut self) {
+        self.etat.en_cours.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl EtatArret {
+    pub fn est_demande(&self) -> bool {
+        self.demande.load(Ordering::SeqCst)
+    }
+
+    pub fn demander_arret(&self) {
+        self.demande.store(true, Ordering::SeqCst);
+    }
+
+    pub fn traitements_en_cours(&self) -> usize {
+        self.en_cours.load(Ordering::SeqCst)
+    }
+
+    /// Debute un traitement. Retourne None si l'arret est demande, le message doit alors etre retenu
+    /// (voir `retenir_message`).
+    pub fn debuter_traitement(&self) -> Option<TraitementEnCours<'_>> {
+        let traitement = self.suivre_traitement();
+        if self.est_demande() {
+            return None
+        }
+        Some(traitement)
+    }
+
+    /// Suit un traitement qui ne peut pas etre refuse (e.g. evenement).
+    pub fn suivre_traitement(&self) -> TraitementEnCours<'_> {
+        self.en_cours.fetch_add(1, Ordering::SeqCst);
+        TraitementEnCours { etat: self }
+    }
+
+    /// Attend la fin des traitements en cours. Retourne false si le delai est expire.
+    pub async fn attendre_traitements(&self, delai: Duration) -> bool {
+        let limite = Instant::now() + delai;
+        loop {
+            let en_cours = self.traitements_en_cours();
+            if en_cours == 0 {
+                return true
+            }
+            if Instant::now() >= limite {
+                warn!("attendre_traitements {} traitements toujours en cours apres {:?}", en_cours, delai);
+                return false
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Retient un message recu pendant l'arret. Le message n'est jamais acquitte et aucune session n'est
+/// ouverte, la tache peut etre interrompue sans perte.
+pub async fn retenir_message<T>() -> T {
+    std::future::pending().await
+}
+
+/// Attend SIGTERM ou ctrl-c.
+pub async fn attendre_signal_arret() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(inner) => inner,
+        Err(e) => {
+            warn!("attendre_signal_arret Erreur installation handler SIGTERM, ctrl-c seulement : {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return
+        }
+    };
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("attendre_signal_arret SIGTERM recu"),
+        _ = tokio::signal::ctrl_c() => info!("attendre_signal_arret ctrl-c recu"),
+    }
+}
+
+/// Execute une tache non critique et la redemarre (avec backoff) si elle se termine ou panique.
+/// Retourne seulement lorsque l'arret est demande.
+pub async fn superviser_tache<F, Fut>(nom: &str, gestionnaire: &DocumentsDomainManager, tache: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut backoff = CONST_BACKOFF_INITIAL;
+    loop {
+        let debut = Instant::now();
+        match AssertUnwindSafe(tache()).catch_unwind().await {
+            Ok(()) => warn!("superviser_tache Tache {} terminee", nom),
+            Err(_) => error!("superviser_tache Tache {} en panique", nom),
+        }
+
+        if gestionnaire.arret.est_demande() {
+            info!("superviser_tache Arret demande, tache {} n'est pas redemarree", nom);
+            return
+        }
+
+        backoff = prochain_backoff(backoff, debut.elapsed());
+        warn!("superviser_tache Redemarrage de la tache {} dans {:?}", nom, backoff);
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Double le delai avant redemarrage, sauf si la tache a fonctionne assez longtemps.
+fn prochain_backoff(backoff: Duration, duree_execution: Duration) -> Duration {
+    if duree_execution >= CONST_DUREE_STABLE {
+        CONST_BACKOFF_INITIAL
+    } else {
+        (backoff * 2).min(CONST_BACKOFF_MAX)
+    }
+}
+
+#[cfg(test)]
+mod test_arret {
+    use super::*;
+    use crate::test_setup::setup;
+
+    #[test]
+    fn test_traitement_refuse_apres_arret() {
+        setup("test_traitement_refuse_apres_arret");
+        let etat = EtatArret::default();
+        {
+            let _traitement = etat.debuter_traitement().expect("traitement");
+            assert_eq!(1, etat.traitements_en_cours());
+        }
+        assert_eq!(0, etat.traitements_en_cours());
+
+        etat.demander_arret();
+        assert!(etat.debuter_traitement().is_none());
+        assert_eq!(0, etat.traitements_en_cours());
+    }
+
+    #[test]
+    fn test_backoff() {
+        setup("test_backoff");
+        let backoff = prochain_backoff(CONST_BACKOFF_INITIAL, Duration::from_secs(1));
+        assert_eq!(Duration::from_secs(10), backoff);
+        assert_eq!(CONST_BACKOFF_MAX, prochain_backoff(Duration::from_secs(200), Duration::from_secs(1)));
+        assert_eq!(CONST_BACKOFF_INITIAL, prochain_backoff(CONST_BACKOFF_MAX, CONST_DUREE_STABLE));
+    }
+}
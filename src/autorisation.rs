@@ -0,0 +1,222 @@
+//! Politique d'autorisation des commandes et requetes du domaine.
+//!
+//! Chaque action est associee a une [`Politique`]. L'autorisation est evaluee une seule fois, avant
+//! l'aiguillage vers le traitement de l'action. Une action absente de la table est refusee.
+
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::constantes::{RolesCertificats, Securite, DELEGATION_GLOBALE_PROPRIETAIRE};
+use millegrilles_common_rust::error::Error;
+
+use crate::constantes::*;
+
+/// Regles d'acces a une action. L'acces est accorde si une des conditions est remplie.
+#[derive(Clone, Debug)]
+pub struct Politique {
+    /// Certificat usager avec le role ComptePrive (et un user_id).
+    pub compte_prive: bool,
+    /// Certificat avec la delegation globale proprietaire.
+    pub delegation_globale: bool,
+    /// Exchanges acceptes pour les certificats de systeme.
+    pub exchanges: &'static [Securite],
+    /// Le certificat doit contenir un user_id, peu importe la condition remplie.
+    pub user_id_requis: bool,
+}
+
+/// Action d'un usager sur ses propres donnees.
+pub const POLITIQUE_USAGER: Politique = Politique {
+    compte_prive: true,
+    delegation_globale: true,
+    exchanges: &[],
+    user_id_requis: true,
+};
+
+/// Requete sur les donnees d'un usager, aussi permise aux systemes 2.prive et 3.protege.
+pub const POLITIQUE_REQUETE_USAGER: Politique = Politique {
+    compte_prive: true,
+    delegation_globale: true,
+    exchanges: &[Securite::L2Prive, Securite::L3Protege],
+    user_id_requis: false,
+};
+
+/// Action d'administration, reservee au proprietaire (delegation globale) et aux systemes 3.protege.
+pub const POLITIQUE_ADMIN: Politique = Politique {
+    compte_prive: false,
+    delegation_globale: true,
+    exchanges: &[Securite::L3Protege],
+    user_id_requis: false,
+};
+
+pub const POLITIQUES_COMMANDES: [(&str, Politique); 14] = [
+    (TRANSACTION_SAUVEGARDER_CATEGORIE_USAGER, POLITIQUE_USAGER),
+    (TRANSACTION_SAUVEGARDER_GROUPE_USAGER, POLITIQUE_USAGER),
+    (TRANSACTION_SAUVEGARDER_DOCUMENT, POLITIQUE_USAGER),
+    (TRANSACTION_SUPPRIMER_DOCUMENT, POLITIQUE_USAGER),
+    (TRANSACTION_RECUPERER_DOCUMENT, POLITIQUE_USAGER),
+    (TRANSACTION_SUPPRIMER_GROUPE, POLITIQUE_USAGER),
+    (TRANSACTION_RECUPERER_GROUPE, POLITIQUE_USAGER),
+    (TRANSACTION_COPIER_DOCUMENT, POLITIQUE_USAGER),
+    (TRANSACTION_COPIER_GROUPE, POLITIQUE_USAGER),
+    (TRANSACTION_MIGRER_CHIFFRAGE, POLITIQUE_USAGER),
+    (COMMANDE_EFFACER_DONNEES_USAGER, POLITIQUE_ADMIN),
+    (COMMANDE_SAUVEGARDER_QUOTA_USAGER, POLITIQUE_ADMIN),
+    (TRANSACTION_REPARER_INTEGRITE, POLITIQUE_ADMIN),
+    (COMMANDE_RECONSTRUIRE_USAGER, POLITIQUE_ADMIN),
+];
+
+pub const POLITIQUES_REQUETES: [(&str, Politique); 14] = [
+    (REQUETE_CATEGORIES_USAGER, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_GROUPES_USAGER, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_GROUPES_CLES, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_DOCUMENTS_CLES, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_DOCUMENTS_GROUPE, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_CAPACITES, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_JOURNAL_ACTIVITE, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_UTILISATION, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_GROUPES_CLES_INCONNUES, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_CHIFFRAGE_OBSOLETE, POLITIQUE_REQUETE_USAGER),
+    (REQUETE_STATISTIQUES_USAGE, POLITIQUE_ADMIN),
+    (REQUETE_STATUT_TACHES, POLITIQUE_ADMIN),
+    (REQUETE_RAPPORT_INTEGRITE, POLITIQUE_ADMIN),
+    (REQUETE_STATUT, POLITIQUE_ADMIN),
+];
+
+/// Proprietes du certificat utilisees pour evaluer une politique.
+#[derive(Clone, Debug, Default)]
+pub struct ContexteAutorisation {
+    pub user_id: Option<String>,
+    pub compte_prive: bool,
+    pub delegation_globale: bool,
+    pub exchanges: Vec<Securite>,
+}
+
+impl ContexteAutorisation {
+    pub fn charger<C>(certificat: &C) -> Result<Self, Error>
+        where C: VerificateurPermissions + ?Sized
+    {
+        let mut exchanges = Vec::new();
+        for exchange in [Securite::L1Public, Securite::L2Prive, Securite::L3Protege, Securite::L4Secure] {
+            if certificat.verifier_exchanges(vec![exchange.clone()])? {
+                exchanges.push(exchange);
+            }
+        }
+
+        Ok(Self {
+            user_id: certificat.get_user_id()?,
+            compte_prive: certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?,
+            delegation_globale: certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)?,
+            exchanges,
+        })
+    }
+}
+
+pub fn evaluer(politique: &Politique, contexte: &ContexteAutorisation) -> bool {
+    if politique.user_id_requis && contexte.user_id.is_none() {
+        return false
+    }
+    if politique.compte_prive && contexte.compte_prive && contexte.user_id.is_some() {
+        return true
+    }
+    if politique.delegation_globale && contexte.delegation_globale {
+        return true
+    }
+    politique.exchanges.iter().any(|e| contexte.exchanges.contains(e))
+}
+
+pub fn trouver_politique<'a>(politiques: &'a [(&str, Politique)], action: &str) -> Option<&'a Politique> {
+    politiques.iter().find(|(a, _)| *a == action).map(|(_, p)| p)
+}
+
+/// Verifie que le certificat est autorise a executer l'action selon la table de politiques.
+pub fn verifier_autorisation<C>(politiques: &[(&str, Politique)], action: &str, certificat: &C) -> Result<(), Error>
+    where C: VerificateurPermissions + ?Sized
+{
+    let politique = match trouver_politique(politiques, action) {
+        Some(inner) => inner,
+        None => Err(Error::String(format!("autorisation.verifier_autorisation Action inconnue : {}", action)))?
+    };
+
+    let contexte = ContexteAutorisation::charger(certificat)?;
+    if evaluer(politique, &contexte) {
+        Ok(())
+    } else {
+        Err(Error::String(format!("autorisation.verifier_autorisation Acces refuse pour action {}", action)))
+    }
+}
+
+#[cfg(test)]
+mod test_autorisation {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn usager_prive() -> ContexteAutorisation {
+        ContexteAutorisation { user_id: Some("z2i3Xjx".to_string()), compte_prive: true, ..Default::default() }
+    }
+
+    fn usager_sans_role() -> ContexteAutorisation {
+        ContexteAutorisation { user_id: Some("z2i3Xjx".to_string()), ..Default::default() }
+    }
+
+    fn proprietaire() -> ContexteAutorisation {
+        ContexteAutorisation { user_id: Some("z2i3Xjx".to_string()), delegation_globale: true, ..Default::default() }
+    }
+
+    fn systeme(exchanges: Vec<Securite>) -> ContexteAutorisation {
+        ContexteAutorisation { exchanges, ..Default::default() }
+    }
+
+    fn est_admin(politique: &Politique) -> bool {
+        !politique.compte_prive && politique.delegation_globale
+    }
+
+    #[test]
+    fn test_commandes_usager() {
+        setup("test_commandes_usager");
+        for (action, politique) in POLITIQUES_COMMANDES.iter().filter(|(_, p)| !est_admin(p)) {
+            assert!(evaluer(politique, &usager_prive()), "compte prive refuse pour {}", action);
+            assert!(evaluer(politique, &proprietaire()), "proprietaire refuse pour {}", action);
+            assert!(!evaluer(politique, &usager_sans_role()), "usager sans role accepte pour {}", action);
+        }
+    }
+
+    #[test]
+    fn test_commandes_systeme_sans_user_id() {
+        setup("test_commandes_systeme_sans_user_id");
+        let contexte = systeme(vec![Securite::L1Public, Securite::L2Prive, Securite::L3Protege, Securite::L4Secure]);
+        for (action, politique) in POLITIQUES_COMMANDES.iter().filter(|(_, p)| !est_admin(p)) {
+            assert!(!evaluer(politique, &contexte), "systeme sans user_id accepte pour {}", action);
+        }
+    }
+
+    #[test]
+    fn test_requetes() {
+        setup("test_requetes");
+        for (action, politique) in POLITIQUES_REQUETES.iter().filter(|(_, p)| !est_admin(p)) {
+            assert!(evaluer(politique, &usager_prive()), "compte prive refuse pour {}", action);
+            assert!(evaluer(politique, &proprietaire()), "proprietaire refuse pour {}", action);
+            assert!(evaluer(politique, &systeme(vec![Securite::L2Prive])), "2.prive refuse pour {}", action);
+            assert!(evaluer(politique, &systeme(vec![Securite::L3Protege])), "3.protege refuse pour {}", action);
+            assert!(!evaluer(politique, &systeme(vec![Securite::L1Public])), "1.public accepte pour {}", action);
+            assert!(!evaluer(politique, &usager_sans_role()), "usager sans role accepte pour {}", action);
+        }
+    }
+
+    #[test]
+    fn test_actions_admin() {
+        setup("test_actions_admin");
+        let politiques = POLITIQUES_COMMANDES.iter().chain(POLITIQUES_REQUETES.iter())
+            .filter(|(_, p)| est_admin(p));
+        for (action, politique) in politiques {
+            assert!(evaluer(politique, &proprietaire()), "proprietaire refuse pour {}", action);
+            assert!(evaluer(politique, &systeme(vec![Securite::L3Protege])), "3.protege refuse pour {}", action);
+            assert!(!evaluer(politique, &systeme(vec![Securite::L2Prive])), "2.prive accepte pour {}", action);
+            assert!(!evaluer(politique, &usager_prive()), "compte prive accepte pour {}", action);
+        }
+    }
+
+    #[test]
+    fn test_action_inconnue() {
+        setup("test_action_inconnue");
+        assert!(trouver_politique(&POLITIQUES_COMMANDES, "actionInconnue").is_none());
+        assert!(trouver_politique(&POLITIQUES_REQUETES, "actionInconnue").is_none());
+    }
+}
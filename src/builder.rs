@@ -13,8 +13,15 @@ use millegrilles_common_rust::tokio::spawn;
 use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::error::Error as CommonError;
 use millegrilles_common_rust::middleware::{charger_certificats_chiffrage, Middleware};
+use millegrilles_common_rust::millegrilles_cryptographie::chiffrage_cles::CleChiffrageHandler;
+use millegrilles_common_rust::redis_dao::RedisDao;
 
+use crate::arret::{attendre_signal_arret, superviser_tache, CONST_DELAI_ARRET};
 use crate::common::*;
+use crate::configuration::ConfigurationDocuments;
+use crate::evenements_maj::republier_outbox_expire;
+use crate::statut::compter_transactions_en_attente;
+use crate::taches::thread_taches_planifiees;
 
 static DOMAIN_MANAGER: StaticCell<DocumentsDomainManager> = StaticCell::new();
 
@@ -41,11 +48,25 @@ pub async fn run() {
     futures.extend(futures_middleware);
     futures.extend(futures_domaine);
 
-    // Demarrer thread d'entretien.
-    futures.push(spawn(thread_entretien(gestionnaire, middleware)));
+    // Demarrer thread d'entretien. Tache non critique, redemarree si elle se termine.
+    let redis = middleware.redis.as_ref();
+    futures.push(spawn(superviser_tache("thread_entretien", gestionnaire, move || thread_entretien(gestionnaire, middleware, redis))));
+    futures.push(spawn(superviser_tache("thread_taches_planifiees", gestionnaire, move || thread_taches_planifiees(gestionnaire, middleware))));
 
-    // Le "await" maintien l'application ouverte. Des qu'une task termine, l'application arrete.
-    futures.next().await;
+    // Le "await" maintien l'application ouverte. Des qu'une task critique termine ou qu'un
+    // signal d'arret est recu, l'application arrete.
+    tokio::select! {
+        _ = futures.next() => warn!("domaine_messages Une task est terminee, arret"),
+        _ = attendre_signal_arret() => info!("domaine_messages Signal d'arret recu"),
+    }
+
+    // Retenir les nouveaux messages et laisser les traitements en cours commiter ou annuler leur session.
+    // Les taches sont interrompues seulement lorsqu'aucun traitement n'a de session ouverte.
+    gestionnaire.arret.demander_arret();
+    info!("domaine_messages Attendre {} traitements en cours", gestionnaire.arret.traitements_en_cours());
+    while !gestionnaire.arret.attendre_traitements(CONST_DELAI_ARRET).await {
+        warn!("domaine_messages Traitements toujours en cours, l'arret continue d'attendre");
+    }
 
     for f in &futures {
         f.abort()
@@ -67,7 +88,9 @@ where M: Middleware + IsConfigNoeud
     let config = middleware.get_configuration_noeud();
     let instance_id = config.instance_id.as_ref().expect("instance_id").to_string();
 
-    let gestionnaire = DocumentsDomainManager::new(instance_id);
+    let configuration = ConfigurationDocuments::charger();
+
+    let gestionnaire = DocumentsDomainManager::new(instance_id, configuration);
     let gestionnaire = DOMAIN_MANAGER.try_init(gestionnaire)
         .expect("gestionnaire init");
 
@@ -82,16 +105,23 @@ where M: Middleware + IsConfigNoeud
     Ok((gestionnaire, futures))
 }
 
-async fn thread_entretien<M>(_gestionnaire: &DocumentsDomainManager, middleware: &M)
+async fn thread_entretien<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, redis: Option<&RedisDao>)
 where M: Middleware
 {
     let mut prochain_chargement_certificats_maitredescles = Utc::now();
     let intervalle_chargement_certificats_maitredescles = chrono::Duration::minutes(5);
+    let mut prochaine_verification_redis = Utc::now();
+    let intervalle_verification_redis = chrono::Duration::minutes(5);
 
     // Attendre 5 secondes pour init bus
     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
 
     loop {
+        if gestionnaire.arret.est_demande() {
+            info!("domaines_core.entretien Arret demande, fin entretien");
+            break
+        }
+
         let maintenant = Utc::now();
 
         // Effectuer entretien
@@ -100,12 +130,42 @@ where M: Middleware
                 Ok(()) => {
                     prochain_chargement_certificats_maitredescles = maintenant + intervalle_chargement_certificats_maitredescles;
                     debug!("domaines_core.entretien Prochain chargement cert maitredescles: {:?}", prochain_chargement_certificats_maitredescles);
+                    let nombre_certificats = middleware.get_publickeys_chiffrage().len();
+                    gestionnaire.statut.maj_certificats_maitredescles(Ok(nombre_certificats));
                 },
-                Err(e) => warn!("domaines_core.entretien Erreur chargement certificats de maitre des cles : {:?}", e)
+                Err(e) => {
+                    warn!("domaines_core.entretien Erreur chargement certificats de maitre des cles : {:?}", e);
+                    gestionnaire.statut.maj_certificats_maitredescles(Err(format!("{:?}", e)));
+                }
             }
 
         }
 
+        // Verifier l'acces a redis (rapporte par getStatut)
+        if let Some(redis) = redis {
+            if prochaine_verification_redis < maintenant {
+                prochaine_verification_redis = maintenant + intervalle_verification_redis;
+                match redis.liste_certificats_fingerprints().await {
+                    Ok(_) => gestionnaire.statut.maj_redis(Ok(())),
+                    Err(e) => {
+                        warn!("domaines_core.entretien Erreur verification redis : {:?}", e);
+                        gestionnaire.statut.maj_redis(Err(format!("{:?}", e)));
+                    }
+                }
+            }
+        }
+
+        // Compter les transactions en attente (rapporte par getStatut)
+        match compter_transactions_en_attente(middleware).await {
+            Ok(nombre) => gestionnaire.statut.maj_transactions_en_attente(nombre),
+            Err(e) => warn!("domaines_core.entretien Erreur compte des transactions en attente : {:?}", e)
+        }
+
+        // Republier les evenements orphelins de l'outbox
+        if let Err(e) = republier_outbox_expire(middleware).await {
+            warn!("domaines_core.entretien Erreur republication outbox : {:?}", e);
+        }
+
         // Sleep
         tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
     }
@@ -1,4 +1,6 @@
-use log::{debug, error};
+use std::time::Instant;
+
+use log::{debug, error, warn};
 use millegrilles_common_rust::bson::doc;
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::constantes::*;
@@ -15,10 +17,19 @@ use millegrilles_common_rust::error::Error;
 use millegrilles_common_rust::messages_generiques::ReponseCommande;
 use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
 use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
 use serde::{Deserialize, Serialize};
+use crate::autorisation::{verifier_autorisation, POLITIQUES_COMMANDES};
 use crate::common::*;
 use crate::constantes::*;
 use crate::domain_manager::DocumentsDomainManager;
+use crate::effacement::{effacer_donnees_usager, exporter_usager, finaliser_export, CommandeEffacerDonneesUsager, ExportUsager};
+use crate::integrite::{analyser_usager, conserver_rapport, TransactionReparerIntegrite};
+use crate::limitation::ClasseAction;
+use crate::reconstruction::{reconstruire_usager, CommandeReconstruireUsager};
+use crate::quotas::{calculer_utilisation, reserver_quota_usager};
+use crate::evenements_maj::*;
+use crate::validation::{valider_cle_attachee, valider_contenu_document, valider_doc_id_client, valider_nouveau_document, verifier_reference_categorie, verifier_references_document};
 
 pub async fn consommer_commande<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
                                    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -26,31 +37,60 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValide, gestionnair
 {
     debug!("consommer_commande : {:?}", &m.type_message);
 
+    let (_, action) = get_domaine_action!(m.type_message);
+
+    // Autorisation selon la politique de l'action
+    verifier_autorisation(&POLITIQUES_COMMANDES, action.as_str(), m.certificat.as_ref())?;
+
+    // Limitation du debit par usager et par certificat, avant d'ouvrir une transaction
     let user_id = m.certificat.get_user_id()?;
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
+    let mut cles_limite = vec![format!("certificat:{}", m.certificat.fingerprint()?)];
+    if let Some(user_id) = user_id.as_ref() {
+        cles_limite.push(format!("usager:{}", user_id));
+    }
+    if let Err(delai) = gestionnaire.limiteur.verifier(&cles_limite, ClasseAction::charger(action.as_str()), Instant::now()) {
+        let retry_after = delai.as_secs_f64().ceil() as u64;
+        warn!("consommer_commande Limite de debit atteinte pour {:?}, retry_after {}s", cles_limite, retry_after);
+        let reponse = json!({"ok": false, "code": ERREUR_LIMITE_DEBIT, "err": "Rate limit exceeded", "retry_after": retry_after});
+        return Ok(Some(middleware.build_reponse(&reponse)?.0))
+    }
 
-    if role_prive && user_id.is_some() {
-        // Ok, commande usager
-    } else {
-        match m.certificat.verifier_exchanges(vec!(Securite::L1Public, Securite::L2Prive, Securite::L3Protege, Securite::L4Secure))? {
-            true => Ok(()),
-            false => {
-                // Verifier si on a un certificat delegation globale
-                match m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-                    true => Ok(()),
-                    false => Err(format!("grosfichiers.consommer_commande: Commande autorisation invalide pour message {:?}", m.type_message)),
-                }
-            }
-        }?;
+    // Les projections de l'usager sont en cours de remplacement (reconstruireUsager)
+    if let Some(user_id) = user_id.as_ref() {
+        if gestionnaire.usagers_bloques.est_bloque(user_id) {
+            warn!("consommer_commande Usager {} bloque par une reconstruction, commande {} refusee", user_id, action);
+            return Ok(Some(middleware.reponse_err(ERREUR_USAGER_BLOQUE, None, Some("User data is being rebuilt, retry later"))?))
+        }
     }
 
-    let (_, action) = get_domaine_action!(m.type_message);
+    // Les evenements de la commande sont identifies dans l'outbox par l'id du message (id de transaction).
+    // L'id de session (lsid) ne convient pas, les sessions sont reutilisees par le pool.
+    let commande_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
+
+    // L'export d'un effacement est ecrit avant la transaction, qui ne fait que l'effacement
+    let export = match action.as_str() {
+        COMMANDE_EFFACER_DONNEES_USAGER => match exporter_effacement(middleware, &m, gestionnaire, &commande_id).await {
+            Ok(inner) => inner,
+            Err(e) => {
+                finaliser_export(&gestionnaire.configuration.repertoire_export, &commande_id, false).await;
+                return Err(e)
+            }
+        },
+        _ => None
+    };
 
     let mut session = middleware.get_session().await?;
     start_transaction_regular(&mut session).await?;
 
     let result = match action.as_str() {
         // Commandes
+        COMMANDE_EFFACER_DONNEES_USAGER => commande_effacer_donnees_usager(middleware, m, gestionnaire, export, &mut session).await,
+        COMMANDE_SAUVEGARDER_QUOTA_USAGER => commande_sauvegarder_quota_usager(middleware, m, &mut session).await,
+        TRANSACTION_REPARER_INTEGRITE => commande_reparer_integrite(middleware, m, gestionnaire, &mut session).await,
+        COMMANDE_RECONSTRUIRE_USAGER => commande_reconstruire_usager(middleware, m, gestionnaire, &mut session).await,
 
         // Transactions
         TRANSACTION_SAUVEGARDER_CATEGORIE_USAGER => commande_sauvegader_categorie(middleware, m, gestionnaire, &mut session).await,
@@ -60,29 +100,39 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValide, gestionnair
         TRANSACTION_RECUPERER_DOCUMENT => commande_recuperer_document(middleware, m, gestionnaire, &mut session).await,
         TRANSACTION_SUPPRIMER_GROUPE => commande_supprimer_groupe(middleware, m, gestionnaire, &mut session).await,
         TRANSACTION_RECUPERER_GROUPE => commande_recuperer_groupe(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_COPIER_DOCUMENT => commande_copier_document(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_COPIER_GROUPE => commande_copier_groupe(middleware, m, gestionnaire, &mut session).await,
+        TRANSACTION_MIGRER_CHIFFRAGE => commande_migrer_chiffrage(middleware, m, gestionnaire, &mut session).await,
 
         // Commandes inconnues
         _ => Err(format!("core_backup.consommer_commande: Commande {} inconnue : {}, message dropped", DOMAINE_NOM, action))?,
     };
 
+    gestionnaire.statut.commande_traitee(result.is_ok());
     match result {
         Ok(result) => {
-            session.commit_transaction().await?;
+            let commit = session.commit_transaction().await;
+            if action.as_str() == COMMANDE_EFFACER_DONNEES_USAGER {
+                // L'export est conserve seulement si l'effacement est commite
+                finaliser_export(&gestionnaire.configuration.repertoire_export, &commande_id, commit.is_ok()).await;
+            }
+            commit?;
+            // Publier les evenements seulement apres le commit
+            if let Err(e) = publier_outbox_commande(middleware, &commande_id).await {
+                error!("consommer_commande Erreur publication outbox, sera republie par l'entretien : {:?}", e);
+            }
             Ok(result)
         }
         Err(e) => {
+            if action.as_str() == COMMANDE_EFFACER_DONNEES_USAGER {
+                finaliser_export(&gestionnaire.configuration.repertoire_export, &commande_id, false).await;
+            }
             session.abort_transaction().await?;
             Err(e)
         }
     }
 }
 
-#[derive(Serialize)]
-struct EvenementMaj {
-    category: Option<TransactionSauvegarderCategorieUsager>,
-    group: Option<TransactionSauvegarderGroupeUsager>,
-}
-
 async fn commande_sauvegader_categorie<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao + ValidateurX509
@@ -99,16 +149,6 @@ async fn commande_sauvegader_categorie<M>(middleware: &M, m: MessageValide, gest
         None => Err(format!("commande_sauvegader_categorie User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-    if role_prive {
-        // Ok
-    } else if m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_sauvegader_categorie: Commande autorisation invalide pour message {:?}", m.type_message))?
-    }
-
     // S'assurer qu'il n'y a pas de conflit de version pour la categorie
     if let Some(categorie_id) = &commande.categorie_id {
         match commande.version {
@@ -136,15 +176,12 @@ async fn commande_sauvegader_categorie<M>(middleware: &M, m: MessageValide, gest
 
     // Injecter le nouveau categorie_id
     if commande.categorie_id.is_none() {
-        commande.categorie_id = Some(message_id);
+        commande.categorie_id = Some(message_id.clone());
     }
 
     // Emettre evenement maj
-    let evenement = EvenementMaj { category: Some(commande), group: None };
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_UPDATE_CATGGROUP, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &evenement).await?;
+    let evenement = EvenementCatGroupV1::categorie(commande);
+    emettre_maj_catgroup(middleware, session, &message_id, &user_id, &evenement).await?;
 
     Ok(reponse_transaction)
 }
@@ -165,16 +202,6 @@ async fn commande_sauvegarder_groupe<M>(middleware: &M, mut m: MessageValide, ge
         None => Err(format!("commande_sauvegader_groupe User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-    if role_prive {
-        // Ok
-    } else if m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_sauvegader_groupe: Commande autorisation invalide pour message {:?}", m.type_message))?
-    }
-
     // S'assurer qu'il n'y a pas de conflit de version pour la categorie
     if let Some(groupe_id) = &commande.groupe_id {
         let filtre = doc! { "groupe_id": groupe_id, "user_id": &user_id };
@@ -190,16 +217,18 @@ async fn commande_sauvegarder_groupe<M>(middleware: &M, mut m: MessageValide, ge
         }
     }
 
+    // La categorie doit exister pour l'usager, sinon le groupe n'apparait dans aucune liste
+    if let Err(e) = verifier_reference_categorie(middleware, user_id.as_str(), commande.categorie_id.as_str(), session).await? {
+        error!("commande_sauvegader_groupe Reference invalide : {:?}", e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+    }
+
     // Traiter la cle
     let mut message_owned = m.message.parse_to_owned()?;
     match message_owned.attachements.take() {
         Some(mut attachements) => match attachements.remove("cle") {
             Some(cle) => {
-                let mut message_cle: MessageMilleGrillesOwned = serde_json::from_value(cle)?;
-                message_cle.verifier_signature()?;
-
-                if let Some(reponse) = transmettre_cle_attachee(middleware, message_cle).await? {
-                    error!("Erreur sauvegarde cle : {:?}", reponse);
+                if let Some(reponse) = valider_transmettre_cle(middleware, cle, commande.cle_id.as_ref()).await? {
                     return Ok(Some(reponse));
                 }
             },
@@ -232,23 +261,15 @@ async fn commande_sauvegarder_groupe<M>(middleware: &M, mut m: MessageValide, ge
 
     // S'assurer de retourner le group_id
     if commande.groupe_id.is_none() {
-        commande.groupe_id = Some(message_id);
+        commande.groupe_id = Some(message_id.clone());
     }
     // Emettre evenement maj
-    let evenement = EvenementMaj { category: None, group: Some(commande) };
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_UPDATE_CATGGROUP, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &evenement).await?;
+    let evenement = EvenementCatGroupV1::groupe(OperationMaj::Sauvegarder, commande);
+    emettre_maj_catgroup(middleware, session, &message_id, &user_id, &evenement).await?;
 
     Ok(resultat)
 }
 
-#[derive(Serialize)]
-struct EvenementDocumentMaj {
-    document: TransactionSauvegarderDocument,
-}
-
 async fn commande_sauvegarder_document<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
                                           -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao + ValidateurX509
@@ -266,17 +287,17 @@ async fn commande_sauvegarder_document<M>(middleware: &M, m: MessageValide, gest
         None => Err(format!("commande_sauvegarder_document User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-    if role_prive {
-        // Ok
-    } else if m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_sauvegarder_document: Commande autorisation invalide pour message {:?}", m.type_message))?
+    // Valider le contenu chiffre (taille, encodage, compression)
+    if let Err(e) = valider_contenu_document(
+        &gestionnaire.configuration, commande.data_chiffre.as_str(), commande.format.clone(), commande.compression.as_ref())
+    {
+        error!("commande_sauvegarder_document Contenu invalide : {:?}", e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
     }
 
     // S'assurer qu'il n'y a pas de conflit de version pour la categorie
+    let mut document_existant = false;
+    let mut taille_existante = 0;
     if let Some(doc_id) = &commande.doc_id {
         let filtre = doc! { "doc_id": doc_id, "user_id": &user_id };
         let collection = middleware.get_collection(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
@@ -288,29 +309,95 @@ async fn commande_sauvegarder_document<M>(middleware: &M, m: MessageValide, gest
                 // return Ok(Some(middleware.formatter_reponse(&reponse, None)?));
                 return Ok(Some(middleware.reponse_err(None, None, Some("Le groupe ne peut pas etre changee"))?))
             }
+            document_existant = true;
+            taille_existante = doc_groupe.data_chiffre.len() as i64;
         }
     }
 
+    // Le groupe doit exister, appartenir a l'usager et ne pas etre supprime. La version de categorie
+    // doit exister pour la categorie du groupe.
+    if let Err(e) = verifier_references_document(
+        middleware, user_id.as_str(), commande.groupe_id.as_str(), commande.categorie_version, session).await?
+    {
+        error!("commande_sauvegarder_document Reference invalide : {:?}", e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+    }
+
+    // Les nouveaux documents ne peuvent pas utiliser l'ancien format de chiffrage (header seulement)
+    if !document_existant {
+        if let Err(e) = valider_nouveau_document(commande.header.as_ref(), commande.nonce.as_ref()) {
+            error!("commande_sauvegarder_document Nouveau document refuse : {:?}", e);
+            return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+        }
+        if let Some(doc_id) = commande.doc_id.as_ref() {
+            if let Err(e) = valider_doc_id_client(doc_id.as_str()) {
+                error!("commande_sauvegarder_document doc_id {} refuse : {:?}", doc_id, e);
+                return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+            }
+        }
+    }
+
+    // Cle propre au document (optionnelle), meme traitement que pour les groupes
+    let mut message_owned = m.message.parse_to_owned()?;
+    if let Some(mut attachements) = message_owned.attachements.take() {
+        if let Some(cle) = attachements.remove("cle") {
+            if let Some(reponse) = valider_transmettre_cle(middleware, cle, commande.cle_id.as_ref()).await? {
+                return Ok(Some(reponse));
+            }
+        }
+    }
+
+    // Verifier et reserver le quota de l'usager. Doit suivre les autres validations : une reponse
+    // d'erreur est commitee avec la reservation.
+    let delta_nombre = if document_existant { 0 } else { 1 };
+    let delta_taille = commande.data_chiffre.len() as i64 - taille_existante;
+    if let Err(e) = reserver_quota_usager(
+        middleware, &gestionnaire.configuration, &user_id, delta_nombre, delta_taille, session).await?
+    {
+        error!("commande_sauvegarder_document Quota usager {} excede : {:?}", user_id, e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+    }
+
     // Traiter la transaction
     let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
 
     // Emettre evenement maj
-    let mut evenement = EvenementDocumentMaj { document: commande };
+    let mut document = commande;
 
     // Check if we set the doc_id from message_id on new document.
-    if evenement.document.doc_id.is_none() {
+    if document.doc_id.is_none() {
         // Set the doc_id from transaction id
-        evenement.document.doc_id = Some(message_id);
+        document.doc_id = Some(message_id.clone());
     }
-    let partition = format!("{}_{}", user_id, evenement.document.groupe_id);
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_UPDATE_GROUPDOCUMENT, vec![Securite::L2Prive])
-        .partition(partition)
-        .build();
-    middleware.emettre_evenement(routage, &evenement).await?;
+    let evenement = EvenementGroupDocumentV1::document(OperationMaj::Sauvegarder, document);
+    emettre_maj_groupdocument(middleware, session, &message_id, &user_id, &evenement).await?;
 
     Ok(resultat)
 }
 
+/// Verifie la cle attachee (signature, domaine, cle_id) et la transmet a MaitreDesCles.
+/// Retourne une reponse d'erreur si la cle est refusee.
+async fn valider_transmettre_cle<M>(middleware: &M, cle: Value, cle_id: Option<&String>)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let mut message_cle: MessageMilleGrillesOwned = serde_json::from_value(cle)?;
+    message_cle.verifier_signature()?;
+
+    // La cle doit etre pour Documents et correspondre au cle_id de l'objet
+    if let Err(e) = valider_cle_attachee(message_cle.contenu.as_str(), cle_id) {
+        error!("valider_transmettre_cle Cle attachee invalide : {:?}", e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+    }
+
+    if let Some(reponse) = transmettre_cle_attachee(middleware, message_cle).await? {
+        error!("Erreur sauvegarde cle : {:?}", reponse);
+        return Ok(Some(reponse));
+    }
+
+    Ok(None)
+}
+
 async fn transmettre_cle_attachee<M>(middleware: &M, message_cle: MessageMilleGrillesOwned)
     -> Result<Option<MessageMilleGrillesBufferDefault>, millegrilles_common_rust::error::Error>
     where M: ValidateurX509 + GenerateurMessages + MongoDao
@@ -351,17 +438,15 @@ async fn transmettre_cle_attachee<M>(middleware: &M, message_cle: MessageMilleGr
     }
 }
 
-#[derive(Serialize)]
-struct EvenementDocumentSupprime {
-    doc_id: String,
-    supprime: bool,
-}
-
 async fn commande_supprimer_document<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao + ValidateurX509
 {
     debug!("commande_supprimer_document Consommer commande : {:?}", m.type_message);
+    let message_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
     let commande: TransactionSupprimerDocument = deser_message_buffer!(m.message);
 
     let doc_id = commande.doc_id.clone();
@@ -371,16 +456,6 @@ async fn commande_supprimer_document<M>(middleware: &M, m: MessageValide, gestio
         None => Err(format!("commande_supprimer_document User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-    if role_prive {
-        // Ok
-    } else if m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_supprimer_document: Commande autorisation invalide pour message {:?}", m.type_message))?
-    }
-
     // Verifier que le document existe et n'est pas supprime.
     let collection = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
     let filtre = doc!{"user_id": &user_id, "doc_id": &commande.doc_id};
@@ -400,14 +475,8 @@ async fn commande_supprimer_document<M>(middleware: &M, m: MessageValide, gestio
     let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
 
     // Emettre evenement maj
-    let evenement = EvenementDocumentSupprime { doc_id, supprime: true };
-
-    // Check if we set the doc_id from message_id on new document.
-    let partition = format!("{}_{}", user_id, groupe_id);
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_UPDATE_GROUPDOCUMENT, vec![Securite::L2Prive])
-        .partition(partition)
-        .build();
-    middleware.emettre_evenement(routage, &evenement).await?;
+    let evenement = EvenementGroupDocumentV1::document_supprime(groupe_id, doc_id, true);
+    emettre_maj_groupdocument(middleware, session, &message_id, &user_id, &evenement).await?;
 
     Ok(resultat)
 }
@@ -417,6 +486,10 @@ async fn commande_recuperer_document<M>(middleware: &M, m: MessageValide, gestio
     where M: GenerateurMessages + MongoDao + ValidateurX509
 {
     debug!("commande_recuperer_document Consommer commande : {:?}", m.type_message);
+    let message_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
     let commande: TransactionSupprimerDocument = deser_message_buffer!(m.message);
 
     let user_id = match m.certificat.get_user_id()? {
@@ -424,25 +497,16 @@ async fn commande_recuperer_document<M>(middleware: &M, m: MessageValide, gestio
         None => Err(format!("commande_recuperer_document User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-    if role_prive {
-        // Ok
-    } else if m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_recuperer_document: Commande autorisation invalide pour message {:?}", m.type_message))?
-    }
-
     // Verifier que le document existe et n'est pas supprime.
     let collection = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
     let filtre = doc!{"user_id": &user_id, "doc_id": &commande.doc_id};
-    if let Some(groupe_existant) = collection.find_one_with_session(filtre, None, session).await? {
-        if Some(true) != groupe_existant.supprime {
+    let groupe_id = if let Some(doc_existant) = collection.find_one_with_session(filtre, None, session).await? {
+        if Some(true) != doc_existant.supprime {
             // Groupe deja recupere
             error!("commande_recuperer_document Erreur document deja recupere");
             return Ok(Some(middleware.reponse_err(1, None, Some("Document already restored"))?));
         }
+        doc_existant.groupe_id
     } else {
         error!("commande_recuperer_document Erreur document inconnu");
         return Ok(Some(middleware.reponse_err(404, None, Some("Unknown document"))?));
@@ -451,29 +515,22 @@ async fn commande_recuperer_document<M>(middleware: &M, m: MessageValide, gestio
     // Traiter la transaction
     let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
 
-    // Emettre evenement maj
-    let evenement = EvenementDocumentSupprime { doc_id: commande.doc_id, supprime: false };
-
-    // Check if we set the doc_id from message_id on new document.
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_UPDATE_GROUPDOCUMENT, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &evenement).await?;
+    // Emettre evenement maj sur la partition du groupe
+    let evenement = EvenementGroupDocumentV1::document_supprime(groupe_id, commande.doc_id, false);
+    emettre_maj_groupdocument(middleware, session, &message_id, &user_id, &evenement).await?;
 
     Ok(resultat)
 }
 
-#[derive(Serialize)]
-struct EvenementGroupeSupprime {
-    groupe_id: String,
-    supprime: bool,
-}
-
 async fn commande_supprimer_groupe<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao + ValidateurX509
 {
     debug!("commande_supprimer_groupe Consommer commande : {:?}", m.type_message);
+    let message_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
     let commande: TransactionSupprimerGroupe = deser_message_buffer!(m.message);
 
     let user_id = match m.certificat.get_user_id()? {
@@ -481,16 +538,6 @@ async fn commande_supprimer_groupe<M>(middleware: &M, m: MessageValide, gestionn
         None => Err(format!("commande_supprimer_groupe User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-    if role_prive {
-        // Ok
-    } else if m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_supprimer_groupe: Commande autorisation invalide pour message {:?}", m.type_message))?
-    }
-
     // Verifier que le document existe et n'est pas supprime.
     let collection = middleware.get_collection_typed::<DocGroupeUsager>(NOM_COLLECTION_GROUPES_USAGERS)?;
     let filtre = doc!{"user_id": &user_id, "groupe_id": &commande.groupe_id};
@@ -509,13 +556,8 @@ async fn commande_supprimer_groupe<M>(middleware: &M, m: MessageValide, gestionn
     let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
 
     // Emettre evenement maj
-    let evenement = EvenementGroupeSupprime { groupe_id: commande.groupe_id, supprime: true };
-
-    // Check if we set the doc_id from message_id on new document.
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_UPDATE_CATGGROUP, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &evenement).await?;
+    let evenement = EvenementCatGroupV1::groupe_supprime(commande.groupe_id, true);
+    emettre_maj_catgroup(middleware, session, &message_id, &user_id, &evenement).await?;
 
     Ok(resultat)
 }
@@ -525,6 +567,10 @@ async fn commande_recuperer_groupe<M>(middleware: &M, m: MessageValide, gestionn
 where M: GenerateurMessages + MongoDao + ValidateurX509
 {
     debug!("commande_recuperer_groupe Consommer commande : {:?}", m.type_message);
+    let message_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
     let commande: TransactionSupprimerGroupe = deser_message_buffer!(m.message);
 
     let user_id = match m.certificat.get_user_id()? {
@@ -532,16 +578,6 @@ where M: GenerateurMessages + MongoDao + ValidateurX509
         None => Err(format!("commande_recuperer_groupe User_id absent du certificat"))?
     };
 
-    // Autorisation: Action usager avec compte prive ou delegation globale
-    let role_prive = m.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-    if role_prive {
-        // Ok
-    } else if m.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("commandes.commande_supprimer_groupe: Commande autorisation invalide pour message {:?}", m.type_message))?
-    }
-
     // Verifier que le document existe et n'est pas supprime.
     let collection = middleware.get_collection_typed::<DocGroupeUsager>(NOM_COLLECTION_GROUPES_USAGERS)?;
     let filtre = doc!{"user_id": &user_id, "groupe_id": &commande.groupe_id};
@@ -560,13 +596,497 @@ where M: GenerateurMessages + MongoDao + ValidateurX509
     let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
 
     // Emettre evenement maj
-    let evenement = EvenementGroupeSupprime { groupe_id: commande.groupe_id, supprime: false };
+    let evenement = EvenementCatGroupV1::groupe_supprime(commande.groupe_id, false);
+    emettre_maj_catgroup(middleware, session, &message_id, &user_id, &evenement).await?;
 
-    // Check if we set the doc_id from message_id on new document.
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_UPDATE_CATGGROUP, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &evenement).await?;
+    Ok(resultat)
+}
+
+/// Une copie sans contenu rechiffre reste lisible dans le groupe de destination si le document a sa propre
+/// cle (conservee par la copie), si la destination est le groupe source ou si les deux groupes ont la meme cle.
+fn copie_cle_compatible(cle_document: Option<&String>, meme_groupe: bool, cle_groupe_source: Option<&String>, cle_groupe_destination: Option<&String>) -> bool {
+    cle_document.is_some()
+        || meme_groupe
+        || matches!((cle_groupe_source, cle_groupe_destination), (Some(source), Some(destination)) if source == destination)
+}
+
+async fn commande_copier_document<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_copier_document Consommer commande : {:?}", m.type_message);
+    let message_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
+    let commande: TransactionCopierDocument = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => Err(format!("commande_copier_document User_id absent du certificat"))?
+    };
+
+    // Verifier que le document source existe et n'est pas supprime.
+    let collection_documents = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+    let filtre = doc!{"user_id": &user_id, "doc_id": &commande.doc_id};
+    let doc_source = match collection_documents.find_one_with_session(filtre, None, session).await? {
+        Some(inner) => {
+            if Some(true) == inner.supprime {
+                error!("commande_copier_document Erreur document source supprime");
+                return Ok(Some(middleware.reponse_err(1, None, Some("Source document is deleted"))?));
+            }
+            inner
+        },
+        None => {
+            error!("commande_copier_document Erreur document source inconnu");
+            return Ok(Some(middleware.reponse_err(404, None, Some("Unknown document"))?));
+        }
+    };
+
+    // Verifier que les groupes source et destination existent et partagent la meme categorie.
+    let collection_groupes = middleware.get_collection_typed::<DocGroupeUsager>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let filtre = doc!{"user_id": &user_id, "groupe_id": &commande.groupe_id};
+    let groupe_destination = match collection_groupes.find_one_with_session(filtre, None, session).await? {
+        Some(inner) => {
+            if Some(true) == inner.supprime {
+                error!("commande_copier_document Erreur groupe destination supprime");
+                return Ok(Some(middleware.reponse_err(2, None, Some("Destination group is deleted"))?));
+            }
+            inner
+        },
+        None => {
+            error!("commande_copier_document Erreur groupe destination inconnu");
+            return Ok(Some(middleware.reponse_err(404, None, Some("Unknown Group"))?));
+        }
+    };
+    let meme_groupe = groupe_destination.groupe_id == doc_source.groupe_id;
+    let mut groupe_source = None;
+    if !meme_groupe {
+        let filtre = doc!{"user_id": &user_id, "groupe_id": &doc_source.groupe_id};
+        groupe_source = collection_groupes.find_one_with_session(filtre, None, session).await?;
+        if let Some(groupe_source) = groupe_source.as_ref() {
+            if groupe_source.categorie_id != groupe_destination.categorie_id {
+                return Ok(Some(middleware.reponse_err(3, None, Some("Destination group has a different category"))?));
+            }
+        }
+    }
+
+    // Valider le contenu rechiffre s'il est fourni
+    if let Some(contenu) = commande.contenu.as_ref() {
+        if let Err(e) = valider_contenu_document(
+            &gestionnaire.configuration, contenu.data_chiffre.as_str(), contenu.format.clone(), contenu.compression.as_ref())
+        {
+            error!("commande_copier_document Contenu invalide : {:?}", e);
+            return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+        }
+    }
+
+    // La copie doit rester lisible dans le groupe de destination. Sinon le contenu doit etre rechiffre
+    // avec sa propre cle, attachee a la commande.
+    let cle_destination = groupe_destination.cle_id.as_ref().or(groupe_destination.ref_hachage_bytes.as_ref());
+    let cles_compatibles = match commande.contenu.as_ref() {
+        // Contenu rechiffre : la cle attachee est requise sauf si c'est la cle du groupe de destination
+        Some(contenu) => matches!((cle_destination, contenu.cle_id.as_ref()), (Some(destination), Some(cle)) if destination == cle),
+        None => {
+            let cle_source = groupe_source.as_ref().and_then(|g| g.cle_id.as_ref().or(g.ref_hachage_bytes.as_ref()));
+            copie_cle_compatible(doc_source.cle_id.as_ref(), meme_groupe, cle_source, cle_destination)
+        }
+    };
+    if !cles_compatibles {
+        let cle_contenu = commande.contenu.as_ref().and_then(|c| c.cle_id.as_ref());
+        let mut message_owned = m.message.parse_to_owned()?;
+        let cle_attachee = message_owned.attachements.take().and_then(|mut a| a.remove("cle"));
+        match (cle_contenu, cle_attachee) {
+            (Some(cle_id), Some(cle)) => {
+                if let Some(reponse) = valider_transmettre_cle(middleware, cle, Some(cle_id)).await? {
+                    return Ok(Some(reponse));
+                }
+            },
+            _ => {
+                error!("commande_copier_document Cle du groupe source differente de la destination, contenu rechiffre et cle requis");
+                return Ok(Some(middleware.reponse_err(4, None, Some("Re-encrypted content and attached key required for destination group"))?));
+            }
+        }
+    }
+
+    // Verifier et reserver le quota de l'usager
+    let taille_copie = match commande.contenu.as_ref() {
+        Some(contenu) => contenu.data_chiffre.len(),
+        None => doc_source.data_chiffre.len()
+    };
+    if let Err(e) = reserver_quota_usager(
+        middleware, &gestionnaire.configuration, &user_id, 1, taille_copie as i64, session).await?
+    {
+        error!("commande_copier_document Quota usager {} excede : {:?}", user_id, e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+    }
+
+    // Traiter la transaction
+    let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
+
+    // Emettre evenement maj pour le groupe de destination
+    let document = match commande.contenu {
+        Some(contenu) => TransactionSauvegarderDocument {
+            doc_id: Some(message_id.clone()),
+            groupe_id: commande.groupe_id,
+            categorie_version: doc_source.categorie_version,
+            data_chiffre: contenu.data_chiffre,
+            cle_id: contenu.cle_id,
+            format: contenu.format,
+            nonce: contenu.nonce,
+            compression: contenu.compression,
+            header: None,
+        },
+        None => TransactionSauvegarderDocument {
+            doc_id: Some(message_id.clone()),
+            groupe_id: commande.groupe_id,
+            categorie_version: doc_source.categorie_version,
+            data_chiffre: doc_source.data_chiffre,
+            cle_id: doc_source.cle_id,
+            format: doc_source.format,
+            nonce: doc_source.nonce,
+            compression: doc_source.compression,
+            header: doc_source.header,
+        }
+    };
+    let evenement = EvenementGroupDocumentV1::document(OperationMaj::Copier, document);
+    emettre_maj_groupdocument(middleware, session, &message_id, &user_id, &evenement).await?;
 
     Ok(resultat)
 }
+
+async fn commande_copier_groupe<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_copier_groupe Consommer commande : {:?}", m.type_message);
+    let message_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
+    let commande: TransactionCopierGroupe = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => Err(format!("commande_copier_groupe User_id absent du certificat"))?
+    };
+
+    // Verifier que le groupe source existe et n'est pas supprime.
+    let collection = middleware.get_collection_typed::<DocGroupeUsager>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let filtre = doc!{"user_id": &user_id, "groupe_id": &commande.groupe_id};
+    let groupe_source = match collection.find_one_with_session(filtre, None, session).await? {
+        Some(inner) => {
+            if Some(true) == inner.supprime {
+                error!("commande_copier_groupe Erreur groupe source supprime");
+                return Ok(Some(middleware.reponse_err(1, None, Some("Source group is deleted"))?));
+            }
+            inner
+        },
+        None => {
+            error!("commande_copier_groupe Erreur groupe source inconnu");
+            return Ok(Some(middleware.reponse_err(404, None, Some("Unknown Group"))?));
+        }
+    };
+
+    // Verifier et reserver le quota de l'usager pour les documents actifs copies (agregation limitee au groupe)
+    let filtre_copie = doc!{"user_id": &user_id, "groupe_id": &commande.groupe_id, "supprime": {"$ne": true}};
+    let copie = calculer_utilisation(middleware, filtre_copie, session).await?;
+    if let Err(e) = reserver_quota_usager(
+        middleware, &gestionnaire.configuration, &user_id, copie.nombre_documents, copie.taille_chiffre, session).await?
+    {
+        error!("commande_copier_groupe Quota usager {} excede : {:?}", user_id, e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+    }
+
+    // Traiter la transaction
+    let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
+
+    // Emettre evenement maj pour le nouveau groupe. La cle du groupe source est conservee.
+    let groupe = match commande.contenu {
+        Some(contenu) => TransactionSauvegarderGroupeUsager {
+            groupe_id: Some(message_id.clone()),
+            categorie_id: groupe_source.categorie_id,
+            data_chiffre: contenu.data_chiffre,
+            cle_id: groupe_source.cle_id,
+            format: contenu.format,
+            nonce: contenu.nonce,
+            header: None,
+            ref_hachage_bytes: groupe_source.ref_hachage_bytes,
+        },
+        None => TransactionSauvegarderGroupeUsager {
+            groupe_id: Some(message_id.clone()),
+            categorie_id: groupe_source.categorie_id,
+            data_chiffre: groupe_source.data_chiffre,
+            cle_id: groupe_source.cle_id,
+            format: groupe_source.format,
+            nonce: groupe_source.nonce,
+            header: groupe_source.header,
+            ref_hachage_bytes: groupe_source.ref_hachage_bytes,
+        }
+    };
+    let evenement = EvenementCatGroupV1::groupe(OperationMaj::Copier, groupe);
+    emettre_maj_catgroup(middleware, session, &message_id, &user_id, &evenement).await?;
+
+    Ok(resultat)
+}
+
+async fn commande_migrer_chiffrage<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_migrer_chiffrage Consommer commande : {:?}", m.type_message);
+    let message_id = {
+        let parsed_message = m.message.parse()?;
+        parsed_message.id.to_owned()
+    };
+    let commande: TransactionMigrerChiffrage = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => Err(format!("commande_migrer_chiffrage User_id absent du certificat"))?
+    };
+
+    if let Err(e) = valider_contenu_document(
+        &gestionnaire.configuration, commande.data_chiffre.as_str(), commande.format.clone(), commande.compression.as_ref())
+    {
+        error!("commande_migrer_chiffrage Contenu invalide : {:?}", e);
+        return Ok(Some(middleware.reponse_err(e.code(), None, Some(e.message()))?))
+    }
+
+    let collection_groupes = middleware.get_collection_typed::<DocGroupeUsager>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let filtre = doc!{"user_id": &user_id, "groupe_id": &commande.groupe_id};
+    let groupe = match collection_groupes.find_one_with_session(filtre, None, session).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(404, None, Some("Unknown Group"))?))
+    };
+
+    // Cle deja connue pour l'objet (aucune cle attachee requise si elle est reutilisee)
+    let document = match commande.doc_id.as_ref() {
+        Some(doc_id) => {
+            let collection_documents = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+            let filtre = doc!{"user_id": &user_id, "groupe_id": &commande.groupe_id, "doc_id": doc_id};
+            let document = match collection_documents.find_one_with_session(filtre, None, session).await? {
+                Some(inner) => inner,
+                None => return Ok(Some(middleware.reponse_err(404, None, Some("Unknown document"))?))
+            };
+            if document.header.is_none() && document.cle_id.is_some() {
+                return Ok(Some(middleware.reponse_err(ERREUR_CHIFFRAGE_COURANT, None, Some("Document already uses current encryption"))?))
+            }
+            Some(document)
+        },
+        None => {
+            if groupe.header.is_none() && groupe.cle_id.is_some() {
+                return Ok(Some(middleware.reponse_err(ERREUR_CHIFFRAGE_COURANT, None, Some("Group already uses current encryption"))?))
+            }
+            // Les documents obsoletes dependent de la cle du groupe (ref_hachage_bytes), les migrer avant.
+            let filtre = doc!{
+                "user_id": &user_id,
+                "groupe_id": &commande.groupe_id,
+                "$or": [{"header": {"$ne": null}}, {"cle_id": null}],
+            };
+            let collection_documents = middleware.get_collection(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+            if collection_documents.count_documents_with_session(filtre, None, session).await? > 0 {
+                return Ok(Some(middleware.reponse_err(ERREUR_DOCUMENTS_OBSOLETES, None, Some("Migrate legacy documents of the group first"))?))
+            }
+            None
+        }
+    };
+
+    // Nouvelle cle attachee, sinon la cle courante du groupe doit etre reutilisee
+    let mut message_owned = m.message.parse_to_owned()?;
+    let cle_attachee = message_owned.attachements.take().and_then(|mut a| a.remove("cle"));
+    match cle_attachee {
+        Some(cle) => {
+            if let Some(reponse) = valider_transmettre_cle(middleware, cle, Some(&commande.cle_id)).await? {
+                return Ok(Some(reponse));
+            }
+        },
+        None => {
+            if document.is_none() || groupe.cle_id.as_ref() != Some(&commande.cle_id) {
+                return Ok(Some(middleware.reponse_err(None, None, Some("Cle manquante"))?))
+            }
+        }
+    }
+
+    // Traiter la transaction
+    let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
+
+    // Emettre evenement maj
+    match document {
+        Some(document) => {
+            let document = TransactionSauvegarderDocument {
+                doc_id: Some(document.doc_id),
+                groupe_id: commande.groupe_id,
+                categorie_version: document.categorie_version,
+                data_chiffre: commande.data_chiffre,
+                cle_id: Some(commande.cle_id),
+                format: commande.format,
+                nonce: Some(commande.nonce),
+                compression: commande.compression,
+                header: None,
+            };
+            let evenement = EvenementGroupDocumentV1::document(OperationMaj::Sauvegarder, document);
+            emettre_maj_groupdocument(middleware, session, &message_id, &user_id, &evenement).await?;
+        },
+        None => {
+            let groupe = TransactionSauvegarderGroupeUsager {
+                groupe_id: Some(commande.groupe_id),
+                categorie_id: groupe.categorie_id,
+                data_chiffre: commande.data_chiffre,
+                cle_id: Some(commande.cle_id),
+                format: commande.format,
+                nonce: Some(commande.nonce),
+                header: None,
+                ref_hachage_bytes: None,
+            };
+            let evenement = EvenementCatGroupV1::groupe(OperationMaj::Sauvegarder, groupe);
+            emettre_maj_catgroup(middleware, session, &message_id, &user_id, &evenement).await?;
+        }
+    }
+
+    Ok(resultat)
+}
+
+/// Exporte les donnees de l'usager vise par la commande d'effacement, hors transaction.
+async fn exporter_effacement<M>(middleware: &M, m: &MessageValide, gestionnaire: &DocumentsDomainManager, commande_id: &str)
+    -> Result<Option<ExportUsager>, Error>
+    where M: MongoDao
+{
+    let commande: CommandeEffacerDonneesUsager = deser_message_buffer!(m.message);
+    if !commande.export.unwrap_or(true) {
+        return Ok(None)
+    }
+    let repertoire = &gestionnaire.configuration.repertoire_export;
+    Ok(Some(exporter_usager(middleware, repertoire, &commande.user_id, commande_id).await?))
+}
+
+async fn commande_effacer_donnees_usager<M>(
+    middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, export: Option<ExportUsager>,
+    session: &mut ClientSession
+)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_effacer_donnees_usager Consommer commande : {:?}", m.type_message);
+    let commande_id = {
+        let parsed = m.message.parse()?;
+        parsed.id.to_owned()
+    };
+    let commande: CommandeEffacerDonneesUsager = deser_message_buffer!(m.message);
+    let demandeur = m.certificat.fingerprint()?;
+
+    let registre = effacer_donnees_usager(gestionnaire, middleware, commande, export, commande_id, demandeur, session).await?;
+    Ok(Some(registre))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct CommandeSauvegarderQuotaUsager {
+    user_id: String,
+    /// Remplacement du nombre maximal de documents. None retire le remplacement.
+    nombre_documents: Option<i64>,
+    /// Remplacement de la taille totale maximale. None retire le remplacement.
+    taille_chiffre: Option<i64>,
+}
+
+async fn commande_sauvegarder_quota_usager<M>(middleware: &M, m: MessageValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("commande_sauvegarder_quota_usager Consommer commande : {:?}", m.type_message);
+    let commande: CommandeSauvegarderQuotaUsager = deser_message_buffer!(m.message);
+
+    let collection = middleware.get_collection(NOM_COLLECTION_QUOTAS_USAGERS)?;
+    let filtre = doc! { "user_id": &commande.user_id };
+    if commande.nombre_documents.is_none() && commande.taille_chiffre.is_none() {
+        // Conserver la ligne, elle contient le compteur d'utilisation
+        let ops = doc! {
+            "$unset": {"nombre_documents": true, "taille_chiffre": true},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        };
+        collection.update_one_with_session(filtre, ops, None, session).await?;
+    } else {
+        let ops = doc! {
+            "$set": {
+                "nombre_documents": commande.nombre_documents,
+                "taille_chiffre": commande.taille_chiffre,
+            },
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection.update_one_with_session(filtre, ops, options, session).await?;
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+async fn commande_reparer_integrite<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_reparer_integrite Consommer commande : {:?}", m.type_message);
+    let commande: TransactionReparerIntegrite = deser_message_buffer!(m.message);
+
+    if let Some(groupe_id) = commande.groupe_id_recuperation.as_ref() {
+        let filtre = doc! { "user_id": &commande.user_id, "groupe_id": groupe_id, "supprime": {"$ne": true} };
+        let collection = middleware.get_collection(NOM_COLLECTION_GROUPES_USAGERS)?;
+        if collection.find_one_with_session(filtre, None, session).await?.is_none() {
+            return Ok(Some(middleware.reponse_err(None, None, Some("Groupe de recuperation inconnu"))?))
+        }
+    }
+
+    let resultat = sauvegarder_traiter_transaction_v2(middleware, m, gestionnaire, session).await?;
+
+    // Les anomalies marquees restent dans le rapport
+    let rapport = analyser_usager(gestionnaire, middleware, &commande.user_id, session).await?;
+    conserver_rapport(middleware, &rapport, session).await?;
+
+    Ok(resultat)
+}
+
+async fn commande_reconstruire_usager<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_reconstruire_usager Consommer commande : {:?}", m.type_message);
+    let commande_id = {
+        let parsed = m.message.parse()?;
+        parsed.id.to_owned()
+    };
+    let commande: CommandeReconstruireUsager = deser_message_buffer!(m.message);
+
+    let rapport = reconstruire_usager(gestionnaire, middleware, &commande, commande_id.as_str(), session).await?;
+    Ok(Some(middleware.build_reponse(&rapport)?.0))
+}
+
+#[cfg(test)]
+mod test_commandes {
+    use super::*;
+    use crate::test_setup::setup;
+
+    #[test]
+    fn test_copie_meme_groupe() {
+        setup("test_copie_meme_groupe");
+        let cle_groupe = String::from("CLE_GROUPE");
+        // Document qui herite de la cle de son groupe, copie dans le meme groupe
+        assert!(copie_cle_compatible(None, true, None, Some(&cle_groupe)));
+    }
+
+    #[test]
+    fn test_copie_cle_document() {
+        setup("test_copie_cle_document");
+        let cle_document = String::from("CLE_DOC");
+        let cle_source = String::from("CLE_1");
+        let cle_destination = String::from("CLE_2");
+        assert!(copie_cle_compatible(Some(&cle_document), false, Some(&cle_source), Some(&cle_destination)));
+    }
+
+    #[test]
+    fn test_copie_cle_groupe() {
+        setup("test_copie_cle_groupe");
+        let cle_1 = String::from("CLE_1");
+        let cle_2 = String::from("CLE_2");
+        assert!(copie_cle_compatible(None, false, Some(&cle_1), Some(&cle_1)));
+        assert!(!copie_cle_compatible(None, false, Some(&cle_1), Some(&cle_2)));
+        assert!(!copie_cle_compatible(None, false, None, Some(&cle_2)));
+    }
+}
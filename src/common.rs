@@ -68,6 +68,23 @@ pub struct DocGroupeUsager {
     // Ancien format de chiffrage (obsolete)
     pub header: Option<String>,
     pub ref_hachage_bytes: Option<String>,
+
+    /// Statistiques des documents du groupe, maintenues par les transactions.
+    #[serde(default)]
+    pub statistiques: Option<StatistiquesGroupe>,
+}
+
+/// Agregats des documents d'un groupe. Permet d'afficher la liste des groupes sans charger les documents.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StatistiquesGroupe {
+    pub nombre_documents: i64,
+    pub nombre_documents_supprimes: i64,
+    /// Taille totale de data_chiffre (bytes) pour tous les documents du groupe.
+    pub taille_chiffre: i64,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub derniere_modification_document: Option<DateTime<Utc>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -116,3 +133,71 @@ pub struct TransactionSupprimerDocument {
 pub struct TransactionSupprimerGroupe {
     pub groupe_id: String,
 }
+
+/// Contenu rechiffre fourni lors d'une copie de document vers un groupe avec une autre cle.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContenuChiffreDocument {
+    pub data_chiffre: String,
+    pub cle_id: Option<String>,
+    #[serde(with="formatchiffragestr")]
+    pub format: FormatChiffrage,
+    pub nonce: Option<String>,
+    pub compression: Option<String>,
+}
+
+/// Copie un document existant vers le meme groupe ou un autre groupe de l'usager.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionCopierDocument {
+    /// Document source
+    pub doc_id: String,
+    /// Groupe de destination
+    pub groupe_id: String,
+    /// Contenu rechiffre avec sa propre cle (attachee a la commande). Requis seulement si le document
+    /// utilise la cle de son groupe et que le groupe de destination a une autre cle.
+    pub contenu: Option<ContenuChiffreDocument>,
+}
+
+/// Contenu rechiffre du groupe copie. La cle du groupe source est conservee.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContenuChiffreGroupe {
+    pub data_chiffre: String,
+    #[serde(with="formatchiffragestr")]
+    pub format: FormatChiffrage,
+    pub nonce: Option<String>,
+}
+
+/// Copie un groupe avec tous ses documents actifs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionCopierGroupe {
+    /// Groupe source
+    pub groupe_id: String,
+    /// Nouveau contenu du groupe (e.g. nouveau nom). Doit etre chiffre avec la meme cle que le groupe source.
+    pub contenu: Option<ContenuChiffreGroupe>,
+}
+
+/// Migration d'un groupe ou d'un document vers le format de chiffrage courant (cle_id et nonce).
+/// Sans doc_id, la migration s'applique au groupe.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionMigrerChiffrage {
+    pub groupe_id: String,
+    pub doc_id: Option<String>,
+    pub data_chiffre: String,
+    pub cle_id: String,
+    #[serde(with="formatchiffragestr")]
+    pub format: FormatChiffrage,
+    pub nonce: String,
+    pub compression: Option<String>,
+}
+
+/// Evenement de suppression d'un compte usager (CoreMaitreDesComptes).
+#[derive(Clone, Debug, Deserialize)]
+pub struct EvenementCompteUsagerSupprime {
+    #[serde(alias = "userId")]
+    pub user_id: String,
+}
+
+/// Transaction interne de purge de toutes les donnees d'un usager.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionSupprimerUsager {
+    pub user_id: String,
+}
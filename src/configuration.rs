@@ -0,0 +1,100 @@
+use std::env;
+use std::path::PathBuf;
+use log::{info, warn};
+
+use crate::constantes::*;
+use crate::limitation::ParametresSeau;
+
+/// Configuration du domaine, chargee a partir de l'environnement au demarrage.
+#[derive(Clone, Debug)]
+pub struct ConfigurationDocuments {
+    /// Taille maximale (bytes) de data_chiffre pour un document.
+    pub taille_max_document: usize,
+    /// Repertoire ou sont ecrits les exports de donnees (effacement d'un usager).
+    pub repertoire_export: PathBuf,
+    /// Nombre maximal de documents par usager (defaut, remplacable par usager).
+    pub quota_nombre_documents: i64,
+    /// Taille totale maximale (bytes) de data_chiffre par usager (defaut, remplacable par usager).
+    pub quota_taille_chiffre: i64,
+    /// Limite de debit des commandes d'ecriture (sauvegarde, suppression, recuperation).
+    pub limite_ecriture: ParametresSeau,
+    /// Limite de debit des commandes de copie.
+    pub limite_copie: ParametresSeau,
+}
+
+impl Default for ConfigurationDocuments {
+    fn default() -> Self {
+        ConfigurationDocuments {
+            taille_max_document: CONST_TAILLE_MAX_DOCUMENT_DEFAUT,
+            repertoire_export: PathBuf::from(CONST_REPERTOIRE_EXPORT_DEFAUT),
+            quota_nombre_documents: CONST_QUOTA_NOMBRE_DOCUMENTS_DEFAUT,
+            quota_taille_chiffre: CONST_QUOTA_TAILLE_CHIFFRE_DEFAUT,
+            limite_ecriture: ParametresSeau { capacite: CONST_LIMITE_ECRITURE_DEFAUT.0, recharge_par_minute: CONST_LIMITE_ECRITURE_DEFAUT.1 },
+            limite_copie: ParametresSeau { capacite: CONST_LIMITE_COPIE_DEFAUT.0, recharge_par_minute: CONST_LIMITE_COPIE_DEFAUT.1 },
+        }
+    }
+}
+
+impl ConfigurationDocuments {
+    pub fn charger() -> Self {
+        let mut configuration = Self::default();
+
+        if let Some(taille) = lire_env_usize(ENV_TAILLE_MAX_DOCUMENT) {
+            // Un document doit toujours tenir dans une batch de streaming.
+            let taille_limite = CONST_STREAMING_BATCH_LEN - CONST_DOCUMENT_META_LEN;
+            if taille > taille_limite {
+                warn!("ConfigurationDocuments {} ({}) depasse la taille de batch, limite a {}", ENV_TAILLE_MAX_DOCUMENT, taille, taille_limite);
+                configuration.taille_max_document = taille_limite;
+            } else {
+                configuration.taille_max_document = taille;
+            }
+        }
+
+        if let Ok(repertoire) = env::var(ENV_REPERTOIRE_EXPORT) {
+            configuration.repertoire_export = PathBuf::from(repertoire);
+        }
+
+        if let Some(nombre) = lire_env_usize(ENV_QUOTA_NOMBRE_DOCUMENTS) {
+            configuration.quota_nombre_documents = nombre as i64;
+        }
+        if let Some(taille) = lire_env_usize(ENV_QUOTA_TAILLE_CHIFFRE) {
+            configuration.quota_taille_chiffre = taille as i64;
+        }
+
+        if let Some(limite) = lire_env_seau(ENV_LIMITE_ECRITURE) {
+            configuration.limite_ecriture = limite;
+        }
+        if let Some(limite) = lire_env_seau(ENV_LIMITE_COPIE) {
+            configuration.limite_copie = limite;
+        }
+
+        info!("ConfigurationDocuments {:?}", configuration);
+        configuration
+    }
+}
+
+fn lire_env_usize(nom: &str) -> Option<usize> {
+    match env::var(nom) {
+        Ok(valeur) => match valeur.parse::<usize>() {
+            Ok(inner) => Some(inner),
+            Err(e) => {
+                warn!("lire_env_usize Valeur invalide pour {} : {:?}", nom, e);
+                None
+            }
+        },
+        Err(_) => None
+    }
+}
+
+/// Lit une limite de debit au format `capacite,recharge_par_minute` (e.g. `120,60`).
+fn lire_env_seau(nom: &str) -> Option<ParametresSeau> {
+    let valeur = env::var(nom).ok()?;
+    let mut parties = valeur.split(',').map(|v| v.trim().parse::<u32>());
+    match (parties.next(), parties.next(), parties.next()) {
+        (Some(Ok(capacite)), Some(Ok(recharge_par_minute)), None) => Some(ParametresSeau { capacite, recharge_par_minute }),
+        _ => {
+            warn!("lire_env_seau Valeur invalide pour {} : {}", nom, valeur);
+            None
+        }
+    }
+}
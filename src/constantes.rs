@@ -4,6 +4,12 @@ pub const NOM_COLLECTION_CATEGORIES_USAGERS: &str = "Documents/categoriesUsagers
 pub const NOM_COLLECTION_CATEGORIES_USAGERS_VERSION: &str = "Documents/categoriesUsagersVersion";
 pub const NOM_COLLECTION_GROUPES_USAGERS: &str = "Documents/groupesUsagers";
 pub const NOM_COLLECTION_DOCUMENTS_USAGERS: &str = "Documents/documentsUsagers";
+pub const NOM_COLLECTION_OUTBOX: &str = "Documents/outbox";
+pub const NOM_COLLECTION_JOURNAL_ACTIVITE: &str = "Documents/journalActivite";
+pub const NOM_COLLECTION_EFFACEMENTS_USAGERS: &str = "Documents/effacementsUsagers";
+pub const NOM_COLLECTION_QUOTAS_USAGERS: &str = "Documents/quotasUsagers";
+pub const NOM_COLLECTION_TACHES: &str = "Documents/taches";
+pub const NOM_COLLECTION_RAPPORTS_INTEGRITE: &str = "Documents/rapportsIntegrite";
 
 pub const NOM_CHAMP_SUPPRIME_DATE: &str = "supprime_date";
 
@@ -18,15 +24,99 @@ pub const TRANSACTION_SUPPRIMER_DOCUMENT: &str = "supprimerDocument";
 pub const TRANSACTION_RECUPERER_DOCUMENT: &str = "recupererDocument";
 pub const TRANSACTION_SUPPRIMER_GROUPE: &str = "supprimerGroupe";
 pub const TRANSACTION_RECUPERER_GROUPE: &str = "recupererGroupe";
+pub const TRANSACTION_COPIER_DOCUMENT: &str = "copierDocument";
+pub const TRANSACTION_COPIER_GROUPE: &str = "copierGroupe";
+pub const TRANSACTION_SUPPRIMER_USAGER: &str = "supprimerUsager";
+pub const TRANSACTION_MIGRER_CHIFFRAGE: &str = "migrerChiffrage";
+pub const TRANSACTION_REPARER_INTEGRITE: &str = "reparerIntegrite";
+
+pub const COMMANDE_EFFACER_DONNEES_USAGER: &str = "effacerDonneesUsager";
+pub const COMMANDE_SAUVEGARDER_QUOTA_USAGER: &str = "sauvegarderQuotaUsager";
+pub const COMMANDE_RECONSTRUIRE_USAGER: &str = "reconstruireUsager";
 
 pub const REQUETE_CATEGORIES_USAGER: &str = "getCategoriesUsager";
 pub const REQUETE_GROUPES_USAGER: &str = "getGroupesUsager";
 pub const REQUETE_GROUPES_CLES: &str = "getClesGroupes";
+pub const REQUETE_DOCUMENTS_CLES: &str = "getClesDocuments";
 pub const REQUETE_DOCUMENTS_GROUPE: &str = "getDocumentsGroupe";
+pub const REQUETE_CAPACITES: &str = "getCapacites";
+pub const REQUETE_JOURNAL_ACTIVITE: &str = "getJournalActivite";
+pub const REQUETE_STATISTIQUES_USAGE: &str = "getStatistiquesUsage";
+pub const REQUETE_UTILISATION: &str = "getUtilisation";
+pub const REQUETE_GROUPES_CLES_INCONNUES: &str = "getGroupesClesInconnues";
+pub const REQUETE_CHIFFRAGE_OBSOLETE: &str = "getChiffrageObsolete";
+pub const REQUETE_STATUT_TACHES: &str = "getStatutTaches";
+pub const REQUETE_RAPPORT_INTEGRITE: &str = "getRapportIntegrite";
+pub const REQUETE_STATUT: &str = "getStatut";
 
 pub const EVENEMENT_UPDATE_CATGGROUP: &str = "updateCatGroup";
 pub const EVENEMENT_UPDATE_GROUPDOCUMENT: &str = "updateGroupDocument";
+pub const EVENEMENT_DONNEES_USAGER_SUPPRIMEES: &str = "donneesUsagerSupprimees";
+
+// Evenements externes
+pub const DOMAINE_COMPTES: &str = "CoreMaitreDesComptes";
+pub const EVENEMENT_COMPTES_USAGER_SUPPRIME: &str = "usagerSupprime";
 
 pub const CONST_STREAMING_BATCH_LEN: usize = 500_000;
 pub const CONST_DOCUMENT_META_LEN: usize = 400;
+/// Delai avant qu'un evenement non publie de l'outbox soit republie par l'entretien.
+pub const CONST_DELAI_REPUBLICATION_OUTBOX_SECS: i64 = 120;
+
+/// Taille maximale par defaut de data_chiffre pour un document. Doit tenir dans une batch de streaming.
+pub const CONST_TAILLE_MAX_DOCUMENT_DEFAUT: usize = 256_000;
+/// Valeurs de compression acceptees pour un document.
+pub const CONST_COMPRESSIONS_SUPPORTEES: [&str; 2] = ["deflate", "gzip"];
+/// Formats de chiffrage acceptes pour le contenu.
+pub const CONST_FORMATS_CHIFFRAGE_SUPPORTES: [&str; 1] = ["mgs4"];
+/// Fonctionnalites optionnelles offertes par ce serveur (rapportees dans getCapacites).
+pub const CONST_FONCTIONNALITES: [&str; 6] = ["streaming", "syncIncrementale", "copie", "projection", "quotas", "clesDocuments"];
+
+pub const CONST_JOURNAL_LIMITE_DEFAUT: i64 = 50;
+pub const CONST_JOURNAL_LIMITE_MAX: i64 = 500;
+
+pub const CONST_CHIFFRAGE_OBSOLETE_LIMITE_DEFAUT: i64 = 1_000;
+pub const CONST_CHIFFRAGE_OBSOLETE_LIMITE_MAX: i64 = 5_000;
+
+pub const CONST_RAPPORTS_INTEGRITE_LIMITE_DEFAUT: i64 = 100;
+pub const CONST_RAPPORTS_INTEGRITE_LIMITE_MAX: i64 = 1_000;
+
+/// Separateur des doc_id derives d'une transaction (copie de groupe) : `{transaction_id}_{index}`.
+/// Les ids de message (hex) et les uuid ne le contiennent pas, un client ne peut pas l'utiliser.
+pub const CONST_SEPARATEUR_DOC_ID_DERIVE: char = '_';
+
+/// Quotas par defaut d'un usager (remplacables par usager).
+pub const CONST_QUOTA_NOMBRE_DOCUMENTS_DEFAUT: i64 = 10_000;
+pub const CONST_QUOTA_TAILLE_CHIFFRE_DEFAUT: i64 = 100_000_000;
+
+/// Limites de debit par defaut (jetons, recharge par minute) par usager et par certificat.
+pub const CONST_LIMITE_ECRITURE_DEFAUT: (u32, u32) = (120, 120);
+pub const CONST_LIMITE_COPIE_DEFAUT: (u32, u32) = (20, 20);
+
+pub const ENV_TAILLE_MAX_DOCUMENT: &str = "MG_DOCUMENTS_TAILLE_MAX_DOCUMENT";
+pub const ENV_REPERTOIRE_EXPORT: &str = "MG_DOCUMENTS_REPERTOIRE_EXPORT";
+pub const ENV_QUOTA_NOMBRE_DOCUMENTS: &str = "MG_DOCUMENTS_QUOTA_NOMBRE_DOCUMENTS";
+pub const ENV_QUOTA_TAILLE_CHIFFRE: &str = "MG_DOCUMENTS_QUOTA_TAILLE_CHIFFRE";
+pub const ENV_LIMITE_ECRITURE: &str = "MG_DOCUMENTS_LIMITE_ECRITURE";
+pub const ENV_LIMITE_COPIE: &str = "MG_DOCUMENTS_LIMITE_COPIE";
+
+/// Repertoire par defaut des exports (volume archives de l'image docker).
+pub const CONST_REPERTOIRE_EXPORT_DEFAUT: &str = "/var/opt/millegrilles/archives";
+
+pub const ERREUR_FORMAT_DATA_CHIFFRE: usize = 400;
+pub const ERREUR_CHIFFRAGE_COURANT: usize = 409;
+pub const ERREUR_HEADER_OBSOLETE: usize = 410;
+pub const ERREUR_CLE_FORMAT: usize = 420;
+pub const ERREUR_CLE_DOMAINE: usize = 421;
+pub const ERREUR_CLE_ID: usize = 422;
+pub const ERREUR_DOC_ID_RESERVE: usize = 423;
+pub const ERREUR_DOCUMENTS_OBSOLETES: usize = 424;
+pub const ERREUR_CATEGORIE_INCONNUE: usize = 430;
+pub const ERREUR_VERSION_CATEGORIE_INCONNUE: usize = 431;
+pub const ERREUR_GROUPE_INCONNU: usize = 432;
+pub const ERREUR_GROUPE_SUPPRIME: usize = 433;
+pub const ERREUR_USAGER_BLOQUE: usize = 434;
+pub const ERREUR_TAILLE_DOCUMENT: usize = 413;
+pub const ERREUR_LIMITE_DEBIT: usize = 429;
+pub const ERREUR_COMPRESSION_INVALIDE: usize = 415;
+pub const ERREUR_QUOTA_EXCEDE: usize = 507;
 
@@ -1,11 +1,14 @@
-use log::{debug, error};
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
 use millegrilles_common_rust::error::Error as CommonError;
 use millegrilles_common_rust::async_trait::async_trait;
+use millegrilles_common_rust::bson::{doc, Bson, Document};
+use millegrilles_common_rust::chrono::Utc;
 use millegrilles_common_rust::backup::BackupStarter;
 use millegrilles_common_rust::certificats::ValidateurX509;
-use millegrilles_common_rust::chrono::Timelike;
 use millegrilles_common_rust::configuration::ConfigMessages;
-use millegrilles_common_rust::constantes::{Securite, DEFAULT_Q_TTL};
+use millegrilles_common_rust::constantes::{Securite, CHAMP_MODIFICATION, DEFAULT_Q_TTL, TRANSACTION_CHAMP_COMPLETE};
 use millegrilles_common_rust::db_structs::TransactionValide;
 use millegrilles_common_rust::domaines_traits::{AiguillageTransactions, ConsommateurMessagesBus, GestionnaireBusMillegrilles, GestionnaireDomaineV2};
 use millegrilles_common_rust::domaines_v2::{prepare_mongodb_domain_indexes, GestionnaireDomaineSimple};
@@ -17,22 +20,57 @@ use millegrilles_common_rust::middleware::{Middleware, MiddlewareMessages};
 use millegrilles_common_rust::mongodb::ClientSession;
 use millegrilles_common_rust::rabbitmq_dao::{ConfigQueue, ConfigRoutingExchange, QueueType};
 use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::tokio_stream::StreamExt;
 
+use crate::arret::{retenir_message, EtatArret};
 use crate::common::*;
+use crate::configuration::ConfigurationDocuments;
 use crate::constantes::*;
+use crate::limitation::LimiteurCommandes;
+use crate::reconstruction::{filtre_transactions_usager, UsagersBloques};
+use crate::statut::StatutService;
 use crate::commandes::consommer_commande;
 use crate::requetes::consommer_requete;
 use crate::evenements::consommer_evenement;
 use crate::transactions::aiguillage_transaction;
+use crate::taches::DeclencheurTaches;
 
 #[derive(Clone)]
 pub struct DocumentsDomainManager {
     pub instance_id: String,
+    pub configuration: ConfigurationDocuments,
+    pub limiteur: Arc<LimiteurCommandes>,
+    pub statut: Arc<StatutService>,
+    pub arret: Arc<EtatArret>,
+    pub taches: Arc<DeclencheurTaches>,
+    pub usagers_bloques: Arc<UsagersBloques>,
+    /// Identifiant de reconstruction. Les transactions sont alors appliquees sur des collections temporaires.
+    pub reconstruction: Option<String>,
 }
 
 impl DocumentsDomainManager {
-    pub fn new(instance_id: String) -> DocumentsDomainManager {
-        DocumentsDomainManager { instance_id }
+    pub fn new(instance_id: String, configuration: ConfigurationDocuments) -> DocumentsDomainManager {
+        let limiteur = Arc::new(LimiteurCommandes::new(&configuration));
+        let statut = Arc::new(StatutService::new());
+        let arret = Arc::new(EtatArret::default());
+        let taches = Arc::new(DeclencheurTaches::default());
+        let usagers_bloques = Arc::new(UsagersBloques::default());
+        DocumentsDomainManager { instance_id, configuration, limiteur, statut, arret, taches, usagers_bloques, reconstruction: None }
+    }
+
+    /// Copie du gestionnaire qui redirige les projections vers les collections temporaires de la reconstruction.
+    pub fn pour_reconstruction(&self, reconstruction_id: &str) -> DocumentsDomainManager {
+        let mut gestionnaire = self.clone();
+        gestionnaire.reconstruction = Some(reconstruction_id.to_string());
+        gestionnaire
+    }
+
+    /// Nom de la collection de projection a utiliser.
+    pub fn nom_collection(&self, nom_collection: &str) -> String {
+        match self.reconstruction.as_ref() {
+            Some(reconstruction_id) => format!("{}/reconstruction/{}", nom_collection, reconstruction_id),
+            None => nom_collection.to_string()
+        }
     }
 }
 
@@ -47,6 +85,7 @@ impl GestionnaireDomaineV2 for DocumentsDomainManager {
             String::from(NOM_COLLECTION_CATEGORIES_USAGERS),
             String::from(NOM_COLLECTION_CATEGORIES_USAGERS_VERSION),
             String::from(NOM_COLLECTION_GROUPES_USAGERS),
+            String::from(NOM_COLLECTION_JOURNAL_ACTIVITE),
         ])
     }
 }
@@ -75,6 +114,11 @@ impl ConsommateurMessagesBus for DocumentsDomainManager {
     where
         M: Middleware
     {
+        // Retenir les nouvelles requetes pendant l'arret, elles seront remises dans la Q
+        let _traitement = match self.arret.debuter_traitement() {
+            Some(inner) => inner,
+            None => return retenir_message().await
+        };
         consommer_requete(middleware, message, self).await
     }
 
@@ -82,6 +126,12 @@ impl ConsommateurMessagesBus for DocumentsDomainManager {
     where
         M: Middleware
     {
+        // Retenir les nouvelles commandes pendant l'arret, elles seront remises dans la Q.
+        // Une commande en cours termine sa transaction.
+        let _traitement = match self.arret.debuter_traitement() {
+            Some(inner) => inner,
+            None => return retenir_message().await
+        };
         consommer_commande(middleware, message, self).await
     }
 
@@ -89,6 +139,8 @@ impl ConsommateurMessagesBus for DocumentsDomainManager {
     where
         M: Middleware
     {
+        // Un evenement refuse serait perdu, il est seulement suivi pour l'arret
+        let _traitement = self.arret.suivre_traitement();
         consommer_evenement(self, middleware, message).await
     }
 }
@@ -113,6 +165,10 @@ impl GestionnaireDomaineSimple for DocumentsDomainManager {
         if let Some(nom_collection_transactions) = self.get_collection_transactions() {
             prepare_mongodb_domain_indexes(middleware, nom_collection_transactions).await?;
             preparer_index_mongodb(middleware).await?;
+            // Diagnostic seulement, un plan sans index ne bloque pas le demarrage
+            if let Err(e) = verifier_plans_requetes(middleware).await {
+                warn!("preparer_database_mongodb Erreur verification des plans de requetes : {:?}", e);
+            }
         }
         Ok(())
     }
@@ -121,15 +177,13 @@ impl GestionnaireDomaineSimple for DocumentsDomainManager {
     where
         M: MiddlewareMessages + BackupStarter + MongoDao
     {
-        // let minute = trigger.get_date().minute();
-        //
-        // // Faire l'aggretation des lectures
-        // // Va chercher toutes les lectures non traitees de l'heure precedente (-65 minutes)
-        // if minute % 15 == 5 {
-        //     if let Err(e) = generer_transactions_lectures_horaires(middleware, self).await {
-        //         error!("traiter_cedule Erreur generer_transactions : {:?}", e);
-        //     }
-        // }
+        if self.arret.est_demande() {
+            debug!("traiter_cedule Arret en cours, taches planifiees ignorees");
+            return Ok(())
+        }
+
+        // Les taches sont executees par thread_taches_planifiees, la cedule n'attend pas
+        self.taches.declencher(trigger.get_date());
 
         Ok(())
     }
@@ -144,12 +198,35 @@ pub fn preparer_queues(manager: &DocumentsDomainManager) -> Vec<QueueType> {
         REQUETE_CATEGORIES_USAGER,
         REQUETE_GROUPES_USAGER,
         REQUETE_GROUPES_CLES,
+        REQUETE_DOCUMENTS_CLES,
         REQUETE_DOCUMENTS_GROUPE,
+        REQUETE_CAPACITES,
+        REQUETE_JOURNAL_ACTIVITE,
+        REQUETE_UTILISATION,
+        REQUETE_GROUPES_CLES_INCONNUES,
+        REQUETE_CHIFFRAGE_OBSOLETE,
     ];
     for req in requetes_privees {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L2Prive});
     }
 
+    // Requetes et commandes d'administration (delegation globale sur 2.prive, systemes 3.protege)
+    let actions_admin: Vec<(&str, &str)> = vec![
+        ("requete", REQUETE_STATISTIQUES_USAGE),
+        ("requete", REQUETE_STATUT_TACHES),
+        ("requete", REQUETE_RAPPORT_INTEGRITE),
+        ("requete", REQUETE_STATUT),
+        ("commande", COMMANDE_EFFACER_DONNEES_USAGER),
+        ("commande", COMMANDE_SAUVEGARDER_QUOTA_USAGER),
+        ("commande", TRANSACTION_REPARER_INTEGRITE),
+        ("commande", COMMANDE_RECONSTRUIRE_USAGER),
+    ];
+    for (type_message, action) in actions_admin {
+        for exchange in [Securite::L2Prive, Securite::L3Protege] {
+            rk_volatils.push(ConfigRoutingExchange {routing_key: format!("{}.{}.{}", type_message, DOMAINE_NOM, action), exchange});
+        }
+    }
+
     let commandes_privees: Vec<&str> = vec![
         // Transactions
         TRANSACTION_SAUVEGARDER_CATEGORIE_USAGER,
@@ -159,11 +236,22 @@ pub fn preparer_queues(manager: &DocumentsDomainManager) -> Vec<QueueType> {
         TRANSACTION_RECUPERER_DOCUMENT,
         TRANSACTION_SUPPRIMER_GROUPE,
         TRANSACTION_RECUPERER_GROUPE,
+        TRANSACTION_COPIER_DOCUMENT,
+        TRANSACTION_COPIER_GROUPE,
+        TRANSACTION_MIGRER_CHIFFRAGE,
     ];
     for cmd in commandes_privees {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L2Prive});
     }
 
+    // Evenements 3.protege
+    let evenements_proteges: Vec<(&str, &str)> = vec![
+        (DOMAINE_COMPTES, EVENEMENT_COMPTES_USAGER_SUPPRIME),
+    ];
+    for (domaine, evenement) in evenements_proteges {
+        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("evenement.{}.{}", domaine, evenement), exchange: Securite::L3Protege});
+    }
+
     let mut queues = Vec::new();
 
     // Queue de messages volatils (requete, commande, evenements)
@@ -219,5 +307,373 @@ where M: MongoDao + ConfigMessages
         Some(options_unique_categories_usager_versions)
     ).await?;
 
+    // Index groupe_id / user_id pour groupes_usager. Les doublons existants sont rapportes si la creation echoue.
+    let options_unique_groupes_usager = IndexOptions {
+        nom_index: Some(String::from("groupe_id_usager")),
+        unique: true
+    };
+    let champs_index_groupes_usager = vec!(
+        ChampIndex {nom_champ: String::from("groupe_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+    );
+    creer_index_unique(
+        middleware,
+        NOM_COLLECTION_GROUPES_USAGERS,
+        champs_index_groupes_usager,
+        options_unique_groupes_usager
+    ).await?;
+
+    // Index cles des groupes (getClesGroupes, cle_id et ancien ref_hachage_bytes)
+    let options_groupes_cle_id = IndexOptions {
+        nom_index: Some(String::from("groupes_usager_cle_id")),
+        unique: false
+    };
+    let champs_index_groupes_cle_id = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("cle_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_GROUPES_USAGERS,
+        champs_index_groupes_cle_id,
+        Some(options_groupes_cle_id)
+    ).await?;
+
+    let options_groupes_ref_hachage_bytes = IndexOptions {
+        nom_index: Some(String::from("groupes_usager_ref_hachage_bytes")),
+        unique: false
+    };
+    let champs_index_groupes_ref_hachage_bytes = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("ref_hachage_bytes"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_GROUPES_USAGERS,
+        champs_index_groupes_ref_hachage_bytes,
+        Some(options_groupes_ref_hachage_bytes)
+    ).await?;
+
+    // Index doc_id / user_id pour documents_usager. Les doublons existants sont rapportes si la creation echoue.
+    let options_unique_documents_usager = IndexOptions {
+        nom_index: Some(String::from("doc_id_usager")),
+        unique: true
+    };
+    let champs_index_documents_usager = vec!(
+        ChampIndex {nom_champ: String::from("doc_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+    );
+    creer_index_unique(
+        middleware,
+        NOM_COLLECTION_DOCUMENTS_USAGERS,
+        champs_index_documents_usager,
+        options_unique_documents_usager
+    ).await?;
+
+    // Index documents d'un groupe et synchronisation incrementale (date de modification)
+    let options_documents_groupe = IndexOptions {
+        nom_index: Some(String::from("documents_usager_groupe_modification")),
+        unique: false
+    };
+    let champs_index_documents_groupe = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("groupe_id"), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_MODIFICATION), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_DOCUMENTS_USAGERS,
+        champs_index_documents_groupe,
+        Some(options_documents_groupe)
+    ).await?;
+
+    // Index cles des documents (getClesDocuments)
+    let options_documents_cle_id = IndexOptions {
+        nom_index: Some(String::from("documents_usager_cle_id")),
+        unique: false
+    };
+    let champs_index_documents_cle_id = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("cle_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_DOCUMENTS_USAGERS,
+        champs_index_documents_cle_id,
+        Some(options_documents_cle_id)
+    ).await?;
+
+    // Index journal d'activite (unique transaction_id, liste par usager)
+    let options_journal_transaction = IndexOptions {
+        nom_index: Some(String::from("journal_transaction_id")),
+        unique: true
+    };
+    let champs_index_journal_transaction = vec!(
+        ChampIndex {nom_champ: String::from("transaction_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_JOURNAL_ACTIVITE,
+        champs_index_journal_transaction,
+        Some(options_journal_transaction)
+    ).await?;
+
+    let options_journal_usager = IndexOptions {
+        nom_index: Some(String::from("journal_usager_date")),
+        unique: false
+    };
+    let champs_index_journal_usager = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("date"), direction: -1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_JOURNAL_ACTIVITE,
+        champs_index_journal_usager,
+        Some(options_journal_usager)
+    ).await?;
+
+    // Index quotas par usager
+    let options_quotas_usager = IndexOptions {
+        nom_index: Some(String::from("quotas_usager")),
+        unique: true
+    };
+    let champs_index_quotas_usager = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_QUOTAS_USAGERS,
+        champs_index_quotas_usager,
+        Some(options_quotas_usager)
+    ).await?;
+
+    // Index outbox par commande (publication apres le commit)
+    let options_outbox_commande = IndexOptions {
+        nom_index: Some(String::from("outbox_commande")),
+        unique: false
+    };
+    let champs_index_outbox_commande = vec!(
+        ChampIndex {nom_champ: String::from("commande_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_OUTBOX,
+        champs_index_outbox_commande,
+        Some(options_outbox_commande)
+    ).await?;
+
+    // Index taches planifiees
+    let options_taches = IndexOptions {
+        nom_index: Some(String::from("taches_nom")),
+        unique: true
+    };
+    let champs_index_taches = vec!(
+        ChampIndex {nom_champ: String::from("nom"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_TACHES,
+        champs_index_taches,
+        Some(options_taches)
+    ).await?;
+
+    // Index transactions d'un usager pour la reconstruction (certificat ou action d'administration)
+    let options_transactions_pubkey = IndexOptions {
+        nom_index: Some(String::from("transactions_pubkey_complete")),
+        unique: false
+    };
+    let champs_index_transactions_pubkey = vec!(
+        ChampIndex {nom_champ: String::from("pubkey"), direction: 1},
+        ChampIndex {nom_champ: String::from(TRANSACTION_CHAMP_COMPLETE), direction: 1},
+        ChampIndex {nom_champ: String::from("estampille"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_TRANSACTIONS,
+        champs_index_transactions_pubkey,
+        Some(options_transactions_pubkey)
+    ).await?;
+
+    let options_transactions_action = IndexOptions {
+        nom_index: Some(String::from("transactions_action_complete")),
+        unique: false
+    };
+    let champs_index_transactions_action = vec!(
+        ChampIndex {nom_champ: String::from("routage.action"), direction: 1},
+        ChampIndex {nom_champ: String::from(TRANSACTION_CHAMP_COMPLETE), direction: 1},
+        ChampIndex {nom_champ: String::from("estampille"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_TRANSACTIONS,
+        champs_index_transactions_action,
+        Some(options_transactions_action)
+    ).await?;
+
+    // Index rapports d'integrite (un rapport par usager)
+    let options_rapports_integrite = IndexOptions {
+        nom_index: Some(String::from("rapports_integrite_usager")),
+        unique: true
+    };
+    let champs_index_rapports_integrite = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        NOM_COLLECTION_RAPPORTS_INTEGRITE,
+        champs_index_rapports_integrite,
+        Some(options_rapports_integrite)
+    ).await?;
+
+    Ok(())
+}
+
+/// Nombre maximal de doublons rapportes lorsqu'un index unique ne peut pas etre cree.
+const CONST_DOUBLONS_RAPPORTES: i64 = 20;
+
+/// Cree un index unique. Si la creation echoue a cause de doublons existants, ils sont rapportes et
+/// l'erreur est retournee : le demarrage echoue jusqu'a la correction des doublons.
+async fn creer_index_unique<M>(middleware: &M, nom_collection: &str, champs_index: Vec<ChampIndex>, options: IndexOptions)
+    -> Result<(), CommonError>
+where M: MongoDao + ConfigMessages
+{
+    let champs: Vec<String> = champs_index.iter().map(|c| c.nom_champ.clone()).collect();
+    let nom_index = options.nom_index.clone();
+    let erreur = match middleware.create_index(middleware, nom_collection, champs_index, Some(options)).await {
+        Ok(()) => return Ok(()),
+        Err(e) => e
+    };
+
+    let doublons = charger_doublons(middleware, nom_collection, &champs).await?;
+    if doublons.is_empty() {
+        return Err(erreur)
+    }
+    for doublon in doublons.iter() {
+        error!("creer_index_unique Doublon dans {} : {}", nom_collection, doublon);
+    }
+    Err(format!("creer_index_unique Index unique {:?} sur {} non cree, doublons existants (au moins {}) : {:?}",
+        nom_index, nom_collection, doublons.len(), erreur))?
+}
+
+/// Groupe les documents par les champs de l'index.
+fn pipeline_doublons(champs: &[String]) -> Vec<Document> {
+    let mut cle = Document::new();
+    for champ in champs {
+        cle.insert(champ.as_str(), format!("${}", champ));
+    }
+    vec![
+        doc! {"$group": {"_id": cle, "nombre": {"$sum": 1}}},
+        doc! {"$match": {"nombre": {"$gt": 1}}},
+        doc! {"$limit": CONST_DOUBLONS_RAPPORTES},
+    ]
+}
+
+async fn charger_doublons<M>(middleware: &M, nom_collection: &str, champs: &[String]) -> Result<Vec<Document>, CommonError>
+where M: MongoDao
+{
+    let collection = middleware.get_collection(nom_collection)?;
+    let mut curseur = collection.aggregate(pipeline_doublons(champs), None).await?;
+    let mut doublons = Vec::new();
+    while let Some(row) = curseur.next().await {
+        doublons.push(row?);
+    }
+    Ok(doublons)
+}
+
+/// Requetes frequentes dont le plan doit utiliser un index.
+fn requetes_indexees() -> Vec<(&'static str, &'static str, Document)> {
+    let cle_ids: Vec<Bson> = Vec::new();
+    vec![
+        ("groupe", NOM_COLLECTION_GROUPES_USAGERS, doc! { "user_id": "", "groupe_id": "" }),
+        ("clesGroupes", NOM_COLLECTION_GROUPES_USAGERS, doc! {
+            "user_id": "",
+            "$or": [{"ref_hachage_bytes": {"$in": &cle_ids}}, {"cle_id": {"$in": &cle_ids}}]
+        }),
+        ("document", NOM_COLLECTION_DOCUMENTS_USAGERS, doc! { "user_id": "", "doc_id": "" }),
+        ("documentsGroupe", NOM_COLLECTION_DOCUMENTS_USAGERS, doc! {
+            "user_id": "", "groupe_id": "", CHAMP_MODIFICATION: {"$gt": Utc::now()}
+        }),
+        ("clesDocuments", NOM_COLLECTION_DOCUMENTS_USAGERS, doc! { "user_id": "", "cle_id": {"$in": &cle_ids} }),
+        ("transactionsUsager", NOM_COLLECTION_TRANSACTIONS, filtre_transactions_usager(&[])),
+        // Index du champ complete cree par prepare_mongodb_domain_indexes
+        ("transactionsEnAttente", NOM_COLLECTION_TRANSACTIONS, doc! { TRANSACTION_CHAMP_COMPLETE: false }),
+    ]
+}
+
+/// Verifie avec explain que les requetes frequentes n'utilisent pas un parcours complet de collection.
+async fn verifier_plans_requetes<M>(middleware: &M) -> Result<(), CommonError>
+where M: MongoDao
+{
+    let database = middleware.get_database()?;
+    for (nom, nom_collection, filtre) in requetes_indexees() {
+        let commande = doc! {
+            "explain": { "find": nom_collection, "filter": filtre },
+            "verbosity": "queryPlanner",
+        };
+        let resultat = database.run_command(commande, None).await?;
+        let plan = match resultat.get_document("queryPlanner") {
+            Ok(inner) => inner,
+            Err(_) => {
+                warn!("verifier_plans_requetes Plan absent pour la requete {}", nom);
+                continue
+            }
+        };
+        if contient_collscan(plan) {
+            warn!("verifier_plans_requetes Requete {} sur {} : parcours complet de collection (COLLSCAN)", nom, nom_collection);
+        } else {
+            debug!("verifier_plans_requetes Requete {} sur {} : index utilise", nom, nom_collection);
+        }
+    }
+    info!("verifier_plans_requetes Verification des plans de requetes completee");
     Ok(())
 }
+
+/// Cherche une etape COLLSCAN dans le plan (incluant les sous-plans $or et les shards).
+fn contient_collscan(plan: &Document) -> bool {
+    plan.iter().any(|(cle, valeur)| match valeur {
+        Bson::String(stage) => cle == "stage" && stage == "COLLSCAN",
+        Bson::Document(inner) => contient_collscan(inner),
+        Bson::Array(liste) => liste.iter().any(|v| match v {
+            Bson::Document(inner) => contient_collscan(inner),
+            _ => false
+        }),
+        _ => false
+    })
+}
+
+#[cfg(test)]
+mod test_domain_manager {
+    use super::*;
+    use crate::test_setup::setup;
+
+    #[test]
+    fn test_pipeline_doublons() {
+        setup("test_pipeline_doublons");
+        let pipeline = pipeline_doublons(&[String::from("doc_id"), String::from("user_id")]);
+        let groupe = pipeline[0].get_document("$group").expect("$group");
+        assert_eq!(&doc! {"doc_id": "$doc_id", "user_id": "$user_id"}, groupe.get_document("_id").expect("_id"));
+    }
+
+    #[test]
+    fn test_plan_index() {
+        setup("test_plan_index");
+        let plan = doc! {
+            "winningPlan": {"stage": "FETCH", "inputStage": {"stage": "IXSCAN", "indexName": "doc_id_usager"}},
+            "rejectedPlans": [],
+        };
+        assert!(!contient_collscan(&plan));
+    }
+
+    #[test]
+    fn test_plan_collscan_or() {
+        setup("test_plan_collscan_or");
+        let plan = doc! {
+            "winningPlan": {"stage": "SUBPLAN", "inputStage": {"stage": "OR", "inputStages": [
+                {"stage": "IXSCAN", "indexName": "groupes_usager_cle_id"},
+                {"stage": "COLLSCAN"},
+            ]}},
+        };
+        assert!(contient_collscan(&plan));
+    }
+}
@@ -0,0 +1,229 @@
+//! Effacement administratif des donnees d'un usager.
+//!
+//! Les donnees de l'usager sont d'abord exportees (jsonl) dans le repertoire d'export avec une lecture
+//! snapshot, hors de la transaction de la commande : un gros export ne depasse pas la duree maximale
+//! d'une transaction Mongo. La transaction de la commande supprime ensuite les donnees de toutes les
+//! collections du domaine et conserve le registre. Si le nombre de documents a change depuis l'export,
+//! l'effacement est refuse. L'export est ecrit dans un fichier partiel (`.partiel`), renomme apres le
+//! commit ou retire si la commande echoue.
+//!
+//! Transactions : seules les transactions de l'usager connues du journal d'activite sont retirees de
+//! `Documents`. Les transactions plus anciennes (sans entree de journal) ne sont PAS retirees : elles
+//! demeurent dans `Documents` et dans les backups, avec leur contenu chiffre. Elles sont seulement
+//! neutralisees par la transaction `supprimerUsager`, qui efface leurs projections lors d'une
+//! regeneration. Le registre d'effacement indique le nombre de transactions retirees.
+//!
+//! Un registre signe par le domaine est conserve dans `Documents/effacementsUsagers` comme preuve.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+use millegrilles_common_rust::bson::{doc, Bson};
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, MessageMilleGrillesBufferDefault, MessageMilleGrillesOwned};
+use millegrilles_common_rust::mongo_dao::{convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::SessionOptions;
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio::fs::{self, File};
+use millegrilles_common_rust::tokio::io::AsyncWriteExt;
+use serde::{Deserialize, Serialize};
+
+use crate::constantes::*;
+use crate::domain_manager::DocumentsDomainManager;
+use crate::evenements::purger_usager;
+
+/// Collections qui contiennent des donnees d'usager (cle `user_id`).
+const COLLECTIONS_USAGER: [&str; 8] = [
+    NOM_COLLECTION_CATEGORIES_USAGERS,
+    NOM_COLLECTION_CATEGORIES_USAGERS_VERSION,
+    NOM_COLLECTION_GROUPES_USAGERS,
+    NOM_COLLECTION_DOCUMENTS_USAGERS,
+    NOM_COLLECTION_JOURNAL_ACTIVITE,
+    NOM_COLLECTION_QUOTAS_USAGERS,
+    NOM_COLLECTION_RAPPORTS_INTEGRITE,
+    NOM_COLLECTION_OUTBOX,
+];
+
+/// Collections d'usager qui ne sont pas des projections (pas dans les collections volatiles). Elles
+/// sont purgees explicitement par la transaction `supprimerUsager`.
+pub const COLLECTIONS_USAGER_NON_VOLATILES: [&str; 3] = [
+    NOM_COLLECTION_QUOTAS_USAGERS,
+    NOM_COLLECTION_RAPPORTS_INTEGRITE,
+    NOM_COLLECTION_OUTBOX,
+];
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommandeEffacerDonneesUsager {
+    pub user_id: String,
+    /// Exporter les donnees avant l'effacement (defaut true).
+    pub export: Option<bool>,
+}
+
+/// Registre d'effacement, signe par le domaine.
+#[derive(Clone, Debug, Serialize)]
+pub struct RegistreEffacementUsager {
+    pub user_id: String,
+    /// Id de la commande d'effacement.
+    pub commande_id: String,
+    /// Fingerprint du certificat qui a demande l'effacement.
+    pub demandeur: String,
+    #[serde(with = "epochseconds")]
+    pub date: DateTime<Utc>,
+    pub fichier_export: Option<String>,
+    /// Nombre de documents supprimes par collection.
+    pub documents_supprimes: HashMap<String, u64>,
+    pub transactions_retirees: u64,
+    /// Cles qui ne sont plus referencees par le domaine.
+    pub cle_ids: Vec<String>,
+}
+
+/// Export des donnees d'un usager, ecrit avant la transaction de l'effacement.
+#[derive(Clone, Debug)]
+pub struct ExportUsager {
+    /// Chemin final du fichier, valide seulement apres [`finaliser_export`].
+    pub chemin: String,
+    /// Nombre de documents exportes par collection.
+    pub documents: HashMap<String, u64>,
+}
+
+/// Efface toutes les donnees de l'usager apres leur export (optionnel). Retourne le registre signe.
+pub async fn effacer_donnees_usager<M>(
+    gestionnaire: &DocumentsDomainManager, middleware: &M, commande: CommandeEffacerDonneesUsager,
+    export: Option<ExportUsager>, commande_id: String, demandeur: String, session: &mut ClientSession
+)
+    -> Result<MessageMilleGrillesBufferDefault, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let user_id = commande.user_id;
+    info!("effacer_donnees_usager Effacement des donnees de l'usager {} (demandeur {})", user_id, demandeur);
+
+    let mut documents_supprimes = HashMap::new();
+    let filtre = doc! { "user_id": &user_id };
+    for nom_collection in COLLECTIONS_USAGER {
+        let collection = middleware.get_collection(nom_collection)?;
+        let compte = collection.count_documents_with_session(filtre.clone(), None, session).await?;
+        documents_supprimes.insert(nom_collection.to_string(), compte);
+    }
+
+    // Les donnees ne doivent pas avoir change depuis l'export
+    let fichier_export = match export {
+        Some(export) => {
+            if export.documents != documents_supprimes {
+                Err(format!("effacer_donnees_usager Donnees de l'usager {} modifiees depuis l'export ({:?} exportes, {:?} courants)",
+                    user_id, export.documents, documents_supprimes))?
+            }
+            Some(export.chemin)
+        },
+        None => None
+    };
+
+    // Le journal est purge avec les donnees, retirer les transactions avant.
+    let transactions_retirees = retirer_transactions_usager(middleware, &user_id, session).await?;
+    let cle_ids = purger_usager(gestionnaire, middleware, &user_id, session).await?;
+
+    let registre = RegistreEffacementUsager {
+        user_id: user_id.clone(),
+        commande_id,
+        demandeur,
+        date: Utc::now(),
+        fichier_export,
+        documents_supprimes,
+        transactions_retirees,
+        cle_ids,
+    };
+
+    let (message, _) = middleware.build_reponse(&registre)?;
+    let message_signe: MessageMilleGrillesOwned = message.parse_to_owned()?;
+    let entree = doc! {
+        "user_id": &user_id,
+        "date": registre.date,
+        "registre": convertir_to_bson(message_signe)?,
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_EFFACEMENTS_USAGERS)?;
+    collection.insert_one_with_session(entree, None, session).await?;
+
+    info!("effacer_donnees_usager Donnees de l'usager {} effacees : {:?}", user_id, registre.documents_supprimes);
+    Ok(message)
+}
+
+fn chemins_export(repertoire: &Path, commande_id: &str) -> (PathBuf, PathBuf) {
+    let chemin = repertoire.join(format!("documents_{}.jsonl", commande_id));
+    let chemin_partiel = repertoire.join(format!("documents_{}.jsonl.partiel", commande_id));
+    (chemin, chemin_partiel)
+}
+
+/// Ecrit toutes les donnees de l'usager dans un fichier jsonl partiel. La lecture utilise une session
+/// snapshot (vue coherente de toutes les collections) hors de toute transaction.
+pub async fn exporter_usager<M>(middleware: &M, repertoire: &Path, user_id: &str, commande_id: &str)
+    -> Result<ExportUsager, Error>
+    where M: MongoDao
+{
+    let (chemin, chemin_partiel) = chemins_export(repertoire, commande_id);
+    let mut fichier = File::create(&chemin_partiel).await
+        .map_err(|e| format!("exporter_usager Erreur creation {:?} : {:?}", chemin_partiel, e))?;
+
+    let options = SessionOptions::builder().snapshot(true).build();
+    let mut session = middleware.get_database()?.client().start_session(options).await?;
+
+    let mut documents = HashMap::new();
+    let filtre = doc! { "user_id": user_id };
+    for nom_collection in COLLECTIONS_USAGER {
+        let collection = middleware.get_collection(nom_collection)?;
+        let mut curseur = collection.find_with_session(filtre.clone(), None, &mut session).await?;
+        let mut compte = 0u64;
+        while let Some(row) = curseur.next(&mut session).await {
+            let ligne = serde_json::json!({"collection": nom_collection, "document": row?});
+            let mut contenu = serde_json::to_vec(&ligne)?;
+            contenu.push(b'\n');
+            fichier.write_all(contenu.as_slice()).await
+                .map_err(|e| format!("exporter_usager Erreur ecriture : {:?}", e))?;
+            compte += 1;
+        }
+        documents.insert(nom_collection.to_string(), compte);
+    }
+    fichier.sync_all().await
+        .map_err(|e| format!("exporter_usager Erreur ecriture : {:?}", e))?;
+
+    let chemin = chemin.to_string_lossy().to_string();
+    info!("exporter_usager Donnees de l'usager {} exportees (partiel) pour {} : {:?}", user_id, chemin, documents);
+    Ok(ExportUsager { chemin, documents })
+}
+
+/// Renomme l'export partiel de la commande apres le commit, ou le retire si la commande a echoue.
+/// Sans effet si la commande n'a pas produit d'export.
+pub async fn finaliser_export(repertoire: &Path, commande_id: &str, commit: bool) {
+    let (chemin, chemin_partiel) = chemins_export(repertoire, commande_id);
+    if !fs::try_exists(&chemin_partiel).await.unwrap_or(false) {
+        return
+    }
+    let resultat = match commit {
+        true => fs::rename(&chemin_partiel, &chemin).await,
+        false => fs::remove_file(&chemin_partiel).await
+    };
+    match resultat {
+        Ok(()) => info!("finaliser_export Export {:?} finalise (commit : {})", chemin, commit),
+        Err(e) => error!("finaliser_export Erreur finalisation {:?} (commit : {}) : {:?}", chemin_partiel, commit, e)
+    }
+}
+
+/// Retire de `Documents` les transactions de l'usager connues du journal d'activite.
+async fn retirer_transactions_usager<M>(middleware: &M, user_id: &str, session: &mut ClientSession)
+    -> Result<u64, Error>
+    where M: MongoDao
+{
+    let collection_journal = middleware.get_collection(NOM_COLLECTION_JOURNAL_ACTIVITE)?;
+    let transaction_ids: Vec<Bson> = collection_journal
+        .distinct_with_session("transaction_id", doc! { "user_id": user_id }, None, session).await?;
+    if transaction_ids.is_empty() {
+        return Ok(0)
+    }
+
+    let collection = middleware.get_collection(NOM_COLLECTION_TRANSACTIONS)?;
+    let resultat = collection.delete_many_with_session(doc! { "id": {"$in": transaction_ids} }, None, session).await?;
+    Ok(resultat.deleted_count)
+}
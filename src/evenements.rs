@@ -1,37 +1,113 @@
-use log::debug;
+use log::{debug, error, info};
 
+use millegrilles_common_rust::bson::{doc, Bson};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::constantes::Securite;
-use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serializable_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
-use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongo_dao::{start_transaction_regular, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
 use millegrilles_common_rust::recepteur_messages::MessageValide;
 use millegrilles_common_rust::error::Error;
 use millegrilles_common_rust::get_domaine_action;
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
+use serde::Serialize;
+
+use crate::common::*;
+use crate::constantes::*;
 use crate::domain_manager::DocumentsDomainManager;
 
-pub async fn consommer_evenement<M>(_gestionnaire: &DocumentsDomainManager, _middleware: &M, m: MessageValide)
+pub async fn consommer_evenement<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, m: MessageValide)
                                     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: ValidateurX509 + GenerateurMessages + MongoDao
 {
     debug!("gestionnaire.consommer_evenement Consommer evenement : {:?}", &m.message);
-    let (_, action) = get_domaine_action!(m.type_message);
-
-    Err(Error::String(format!("gestionnaire.consommer_evenement: Action inconnue : {}", action)))
-
-    // // Autorisation selon l'action
-    // let niveau_securite_requis = match action.as_str() {
-    //     // EVENEMENT_UPLOAD_ATTACHMENT => Ok(Securite::L1Public),
-    //     _ => Err(format!("gestionnaire.consommer_evenement: Action inconnue : {}", action)),
-    // }?;
-    //
-    // if m.certificat.verifier_exchanges(vec![niveau_securite_requis.clone()]) {
-    //     match action.as_str() {
-    //         _ => Err(format!("gestionnaire.consommer_transaction: Mauvais type d'action pour un evenement 1.public : {}", action))?,
-    //     }
-    // } else {
-    //     Err(format!("gestionnaire.consommer_evenement: Niveau de securite invalide pour action {} : doit etre {:?}",
-    //                 action.as_str(), niveau_securite_requis))?
-    // }
+    let (domaine, action) = get_domaine_action!(m.type_message);
+
+    match domaine.as_str() {
+        DOMAINE_COMPTES => match action.as_str() {
+            EVENEMENT_COMPTES_USAGER_SUPPRIME => evenement_compte_usager_supprime(gestionnaire, middleware, m).await,
+            _ => Err(Error::String(format!("gestionnaire.consommer_evenement: Action inconnue : {}", action)))
+        },
+        _ => Err(Error::String(format!("gestionnaire.consommer_evenement: Domaine inconnu : {}", domaine)))
+    }
+}
+
+#[derive(Serialize)]
+struct EvenementDonneesUsagerSupprimees {
+    user_id: String,
+    /// Cles qui ne sont plus referencees par le domaine.
+    cle_ids: Vec<String>,
+}
+
+/// Purge toutes les donnees d'un usager dont le compte a ete supprime.
+async fn evenement_compte_usager_supprime<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, m: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    debug!("evenement_compte_usager_supprime Consommer evenement : {:?}", m.type_message);
+
+    // Autorisation : evenement emis par CoreMaitreDesComptes sur 3.protege
+    if !m.certificat.verifier_exchanges(vec![Securite::L3Protege])? ||
+        !m.certificat.verifier_domaines(vec![DOMAINE_COMPTES.to_string()])?
+    {
+        Err(format!("evenements.evenement_compte_usager_supprime Autorisation invalide pour {:?}", m.type_message))?
+    }
+
+    let evenement: EvenementCompteUsagerSupprime = deser_message_buffer!(m.message);
+    let user_id = evenement.user_id;
+    info!("evenement_compte_usager_supprime Purger donnees de l'usager {}", user_id);
+
+    let mut session = middleware.get_session().await?;
+    start_transaction_regular(&mut session).await?;
+
+    let cle_ids = match purger_usager(gestionnaire, middleware, &user_id, &mut session).await {
+        Ok(inner) => {
+            session.commit_transaction().await?;
+            inner
+        },
+        Err(e) => {
+            error!("evenement_compte_usager_supprime Erreur purge usager {} : {:?}", user_id, e);
+            session.abort_transaction().await?;
+            Err(e)?
+        }
+    };
+
+    // Emettre evenement de completion
+    let evenement = EvenementDonneesUsagerSupprimees { user_id, cle_ids };
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_DONNEES_USAGER_SUPPRIMEES, vec![Securite::L3Protege])
+        .build();
+    middleware.emettre_evenement(routage, &evenement).await?;
+
+    Ok(None)
+}
+
+pub async fn purger_usager<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, user_id: &str, session: &mut ClientSession)
+    -> Result<Vec<String>, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    // Conserver les references de cles avant la purge
+    let mut cle_ids = Vec::new();
+    let filtre = doc! { "user_id": user_id };
+    for nom_collection in [NOM_COLLECTION_GROUPES_USAGERS, NOM_COLLECTION_DOCUMENTS_USAGERS] {
+        let collection = middleware.get_collection(nom_collection)?;
+        for champ in ["cle_id", "ref_hachage_bytes"] {
+            for valeur in collection.distinct_with_session(champ, filtre.clone(), None, session).await? {
+                if let Bson::String(cle_id) = valeur {
+                    if !cle_ids.contains(&cle_id) {
+                        cle_ids.push(cle_id);
+                    }
+                }
+            }
+        }
+    }
+
+    // La purge est une transaction pour etre rejouee lors d'une regeneration.
+    let transaction = TransactionSupprimerUsager { user_id: user_id.to_owned() };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, session, DOMAINE_NOM, TRANSACTION_SUPPRIMER_USAGER).await?;
+
+    Ok(cle_ids)
 }
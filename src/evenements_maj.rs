@@ -0,0 +1,244 @@
+//! Catalogue des evenements de mise a jour emis par le domaine Documents.
+//!
+//! Tous les evenements sont emis sur l'exchange 2.prive. Les commandes emettent les evenements
+//! `updateCatGroup` et `updateGroupDocument`. Les transactions de sauvegarde emettent aussi les
+//! evenements historiques (nom de la transaction, objet de la projection), conserves pour les clients
+//! existants. Aucun evenement n'est emis lors de la reconstruction d'un usager.
+//!
+//! Les evenements sont d'abord ecrits dans l'outbox (`Documents/outbox`) avec la session de la
+//! commande et l'id de la commande (id du message). Ils sont publies apres le commit; les entrees
+//! orphelines sont republiees par l'entretien. Chaque entree est reclamee (`date_claim`) avant sa
+//! publication pour qu'une seule instance la publie. Une reclamation expiree (arret pendant la
+//! publication) peut etre reprise : la publication est au moins une fois.
+//!
+//! | Routing key                                      | Partition               | Payload                      |
+//! |--------------------------------------------------|-------------------------|------------------------------|
+//! | `evenement.Documents.updateCatGroup`             | `{user_id}`             | [`EvenementCatGroupV1`]      |
+//! | `evenement.Documents.updateGroupDocument`        | `{user_id}_{groupe_id}` | [`EvenementGroupDocumentV1`] |
+//! | `evenement.Documents.sauvegarderCategorieUsager` | `{user_id}`             | categorie (projection)       |
+//! | `evenement.Documents.sauvegarderGroupeUsager`    | `{user_id}`             | groupe (projection)          |
+//! | `evenement.Documents.sauvegarderDocument`        | `{user_id}`             | document (projection)        |
+//!
+//! Regle de partition des evenements `update*` : les categories et groupes sont publies sur la
+//! partition de l'usager, les documents sur la partition de leur groupe. Toutes les operations
+//! (creation, maj, suppression, recuperation, copie) suivent la meme regle. Les payloads V1 ajoutent
+//! `version` et `operation` aux champs existants (`category`, `group`, `document`, `doc_id`,
+//! `groupe_id`, `supprime`), sans en retirer.
+
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::bson::oid::ObjectId;
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::{Securite, CHAMP_CREATION};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{opt_chrono_datetime_as_bson_datetime, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::serde_json::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::common::*;
+use crate::constantes::*;
+
+/// Version courante des payloads d'evenements. Incrementer lors d'un changement incompatible.
+pub const VERSION_EVENEMENTS_MAJ: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationMaj {
+    Sauvegarder,
+    Supprimer,
+    Recuperer,
+    Copier,
+}
+
+/// Evenement de maj d'une categorie ou d'un groupe (partition `{user_id}`).
+#[derive(Clone, Debug, Serialize)]
+pub struct EvenementCatGroupV1 {
+    pub version: u32,
+    pub operation: OperationMaj,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<TransactionSauvegarderCategorieUsager>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<TransactionSauvegarderGroupeUsager>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groupe_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supprime: Option<bool>,
+}
+
+impl EvenementCatGroupV1 {
+    pub fn categorie(categorie: TransactionSauvegarderCategorieUsager) -> Self {
+        Self { version: VERSION_EVENEMENTS_MAJ, operation: OperationMaj::Sauvegarder, category: Some(categorie), group: None, groupe_id: None, supprime: None }
+    }
+
+    pub fn groupe(operation: OperationMaj, groupe: TransactionSauvegarderGroupeUsager) -> Self {
+        let groupe_id = groupe.groupe_id.clone();
+        Self { version: VERSION_EVENEMENTS_MAJ, operation, category: None, group: Some(groupe), groupe_id, supprime: None }
+    }
+
+    pub fn groupe_supprime<S>(groupe_id: S, supprime: bool) -> Self
+        where S: ToString
+    {
+        let operation = if supprime { OperationMaj::Supprimer } else { OperationMaj::Recuperer };
+        Self { version: VERSION_EVENEMENTS_MAJ, operation, category: None, group: None, groupe_id: Some(groupe_id.to_string()), supprime: Some(supprime) }
+    }
+}
+
+/// Evenement de maj d'un document (partition `{user_id}_{groupe_id}`).
+#[derive(Clone, Debug, Serialize)]
+pub struct EvenementGroupDocumentV1 {
+    pub version: u32,
+    pub operation: OperationMaj,
+    pub groupe_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<TransactionSauvegarderDocument>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doc_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supprime: Option<bool>,
+}
+
+impl EvenementGroupDocumentV1 {
+    pub fn document(operation: OperationMaj, document: TransactionSauvegarderDocument) -> Self {
+        let groupe_id = document.groupe_id.clone();
+        let doc_id = document.doc_id.clone();
+        Self { version: VERSION_EVENEMENTS_MAJ, operation, groupe_id, document: Some(document), doc_id, supprime: None }
+    }
+
+    pub fn document_supprime<S, T>(groupe_id: S, doc_id: T, supprime: bool) -> Self
+        where S: ToString, T: ToString
+    {
+        let operation = if supprime { OperationMaj::Supprimer } else { OperationMaj::Recuperer };
+        Self { version: VERSION_EVENEMENTS_MAJ, operation, groupe_id: groupe_id.to_string(), document: None, doc_id: Some(doc_id.to_string()), supprime: Some(supprime) }
+    }
+}
+
+/// Partition des evenements de documents d'un groupe.
+pub fn partition_groupe(user_id: &str, groupe_id: &str) -> String {
+    format!("{}_{}", user_id, groupe_id)
+}
+
+/// Ajoute un evenement de categorie/groupe a l'outbox de la commande courante.
+pub async fn emettre_maj_catgroup<M>(middleware: &M, session: &mut ClientSession, commande_id: &str, user_id: &str, evenement: &EvenementCatGroupV1)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    ajouter_outbox(middleware, session, commande_id, EVENEMENT_UPDATE_CATGGROUP, user_id, user_id.to_string(), evenement).await
+}
+
+/// Ajoute un evenement de document a l'outbox de la commande courante.
+pub async fn emettre_maj_groupdocument<M>(middleware: &M, session: &mut ClientSession, commande_id: &str, user_id: &str, evenement: &EvenementGroupDocumentV1)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let partition = partition_groupe(user_id, evenement.groupe_id.as_str());
+    ajouter_outbox(middleware, session, commande_id, EVENEMENT_UPDATE_GROUPDOCUMENT, user_id, partition, evenement).await
+}
+
+/// Ajoute a l'outbox l'evenement historique d'une transaction de sauvegarde (partition `{user_id}`).
+/// L'id de la transaction est l'id du message de la commande.
+pub async fn emettre_maj_transaction<M, S>(middleware: &M, session: &mut ClientSession, transaction_id: &str, action: &str, user_id: &str, document: &S)
+    -> Result<(), Error>
+    where M: MongoDao, S: Serialize
+{
+    ajouter_outbox(middleware, session, transaction_id, action, user_id, user_id.to_string(), document).await
+}
+
+/// Evenement en attente de publication. Il est ecrit dans la meme session mongo que la commande
+/// et n'est publie qu'apres le commit.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EvenementOutbox {
+    #[serde(rename = "_id", skip_serializing)]
+    id: Option<ObjectId>,
+    /// Id du message de la commande (id de transaction)
+    commande_id: String,
+    /// Usager concerne, permet de retirer ses evenements lors d'un effacement.
+    user_id: String,
+    action: String,
+    partition: String,
+    /// Payload serialise en json
+    contenu: String,
+    #[serde(rename = "_mg-creation", with = "chrono_datetime_as_bson_datetime")]
+    creation: DateTime<Utc>,
+    /// Date de reclamation par une instance en cours de publication.
+    #[serde(default, skip_serializing, deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    date_claim: Option<DateTime<Utc>>,
+}
+
+async fn ajouter_outbox<M, S>(middleware: &M, session: &mut ClientSession, commande_id: &str, action: &str, user_id: &str, partition: String, evenement: &S)
+    -> Result<(), Error>
+    where M: MongoDao, S: Serialize
+{
+    let entree = EvenementOutbox {
+        id: None,
+        commande_id: commande_id.to_string(),
+        user_id: user_id.to_string(),
+        action: action.to_string(),
+        partition,
+        contenu: serde_json::to_string(evenement)?,
+        creation: Utc::now(),
+        date_claim: None,
+    };
+    let collection = middleware.get_collection_typed::<EvenementOutbox>(NOM_COLLECTION_OUTBOX)?;
+    collection.insert_one_with_session(entree, None, session).await?;
+    Ok(())
+}
+
+/// Publie les evenements de l'outbox produits par une commande. Appeler apres le commit.
+pub async fn publier_outbox_commande<M>(middleware: &M, commande_id: &str) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let filtre = doc! { "commande_id": commande_id };
+    publier_outbox(middleware, filtre).await
+}
+
+/// Republie les evenements restes dans l'outbox (e.g. arret entre le commit et la publication).
+pub async fn republier_outbox_expire<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let date_expiration = Utc::now() - Duration::seconds(CONST_DELAI_REPUBLICATION_OUTBOX_SECS);
+    let filtre = doc! { CHAMP_CREATION: {"$lt": date_expiration} };
+    publier_outbox(middleware, filtre).await
+}
+
+/// Reclame et publie une a une les entrees correspondant au filtre. Une entree reclamee par une autre
+/// instance est ignoree jusqu'a l'expiration de sa reclamation.
+async fn publier_outbox<M>(middleware: &M, filtre: Document) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let collection = middleware.get_collection_typed::<EvenementOutbox>(NOM_COLLECTION_OUTBOX)?;
+    loop {
+        let maintenant = Utc::now();
+        let expiration_claim = maintenant - Duration::seconds(CONST_DELAI_REPUBLICATION_OUTBOX_SECS);
+        let filtre_claim = doc! {
+            "$and": [
+                filtre.clone(),
+                {"$or": [{"date_claim": {"$exists": false}}, {"date_claim": {"$lt": expiration_claim}}]},
+            ]
+        };
+        let ops = doc! { "$set": {"date_claim": maintenant} };
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { CHAMP_CREATION: 1, "_id": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        let entree = match collection.find_one_and_update(filtre_claim, ops, options).await? {
+            Some(inner) => inner,
+            None => break
+        };
+
+        let contenu: Value = serde_json::from_str(entree.contenu.as_str())?;
+        let routage = RoutageMessageAction::builder(DOMAINE_NOM, entree.action.as_str(), vec![Securite::L2Prive])
+            .partition(entree.partition)
+            .build();
+        middleware.emettre_evenement(routage, &contenu).await?;
+
+        // Publication au moins une fois : retirer l'entree seulement apres l'emission.
+        if let Some(id) = entree.id {
+            collection.delete_one(doc! { "_id": id }, None).await?;
+        }
+    }
+    Ok(())
+}
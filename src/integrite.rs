@@ -0,0 +1,277 @@
+//! Verification de l'integrite referentielle des donnees d'un usager.
+//!
+//! Anomalies detectees :
+//! * document dont le groupe n'existe pas (`documents_groupe_inconnu`);
+//! * document actif dans un groupe supprime (`documents_groupe_supprime`, rapporte seulement);
+//! * groupe dont la categorie n'existe pas (`groupes_categorie_inconnue`);
+//! * document dont la `categorie_version` n'existe pas pour la categorie de son groupe
+//!   (`documents_version_inconnue`).
+//!
+//! La reparation (transaction `reparerIntegrite`) deplace les documents orphelins vers un groupe de
+//! recuperation choisi par le client, seulement si leur cle_id est la cle du groupe de recuperation
+//! (sinon le document ne serait pas dechiffrable dans ce groupe). Les autres documents orphelins et
+//! anomalies sont marques (champ `integrite`) pour attention. La transaction recalcule les anomalies
+//! lors d'une regeneration.
+//!
+//! Les rapports (`Documents/rapportsIntegrite`) ne sont pas des projections : ils sont conserves lors
+//! d'une regeneration et remplaces par la prochaine verification planifiee.
+
+use std::collections::{HashMap, HashSet};
+
+use log::{debug, info};
+use millegrilles_common_rust::bson::{doc, Bson, Document};
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::epochseconds;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::serde_json;
+use serde::{Deserialize, Serialize};
+
+use crate::constantes::*;
+use crate::domain_manager::DocumentsDomainManager;
+use crate::transactions::maj_statistiques_groupe;
+
+const ANOMALIE_GROUPE_INCONNU: &str = "groupeInconnu";
+const ANOMALIE_VERSION_INCONNUE: &str = "versionInconnue";
+const ANOMALIE_CATEGORIE_INCONNUE: &str = "categorieInconnue";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RapportIntegrite {
+    pub user_id: String,
+    #[serde(with = "epochseconds")]
+    pub date: DateTime<Utc>,
+    pub documents_groupe_inconnu: Vec<String>,
+    pub documents_groupe_supprime: Vec<String>,
+    pub groupes_categorie_inconnue: Vec<String>,
+    pub documents_version_inconnue: Vec<String>,
+}
+
+impl RapportIntegrite {
+    pub fn nombre_anomalies(&self) -> usize {
+        self.documents_groupe_inconnu.len() + self.documents_groupe_supprime.len() +
+            self.groupes_categorie_inconnue.len() + self.documents_version_inconnue.len()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionReparerIntegrite {
+    pub user_id: String,
+    /// Groupe existant de l'usager qui recoit les documents orphelins chiffres avec sa cle. Les autres
+    /// documents orphelins (ou tous, sans groupe) sont marques.
+    pub groupe_id_recuperation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CategorieRef { categorie_id: String }
+
+#[derive(Deserialize)]
+struct VersionRef { categorie_id: String, version: i32 }
+
+#[derive(Deserialize)]
+struct GroupeRef { groupe_id: String, categorie_id: String, supprime: Option<bool> }
+
+#[derive(Deserialize)]
+struct DocumentRef {
+    doc_id: String,
+    groupe_id: String,
+    categorie_version: i32,
+    supprime: Option<bool>,
+    cle_id: Option<String>,
+}
+
+/// Analyse les donnees de l'usager.
+pub async fn analyser_usager<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, user_id: &str, session: &mut ClientSession)
+    -> Result<RapportIntegrite, Error>
+    where M: MongoDao
+{
+    let (rapport, _) = analyser(gestionnaire, middleware, user_id, session).await?;
+    Ok(rapport)
+}
+
+async fn analyser<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, user_id: &str, session: &mut ClientSession)
+    -> Result<(RapportIntegrite, HashMap<String, DocumentRef>), Error>
+    where M: MongoDao
+{
+    let filtre = doc! { "user_id": user_id };
+
+    let mut categories = HashSet::new();
+    let options = FindOptions::builder().projection(doc! {"categorie_id": 1}).build();
+    let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_CATEGORIES_USAGERS).as_str())?;
+    let mut curseur = collection.find_with_session(filtre.clone(), options, session).await?;
+    while let Some(row) = curseur.next(session).await {
+        let categorie: CategorieRef = convertir_bson_deserializable(row?)?;
+        categories.insert(categorie.categorie_id);
+    }
+
+    let mut versions = HashSet::new();
+    let options = FindOptions::builder().projection(doc! {"categorie_id": 1, "version": 1}).build();
+    let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_CATEGORIES_USAGERS_VERSION).as_str())?;
+    let mut curseur = collection.find_with_session(filtre.clone(), options, session).await?;
+    while let Some(row) = curseur.next(session).await {
+        let version: VersionRef = convertir_bson_deserializable(row?)?;
+        versions.insert((version.categorie_id, version.version));
+    }
+
+    let mut rapport = RapportIntegrite { user_id: user_id.to_string(), date: Utc::now(), ..Default::default() };
+
+    let mut groupes = HashMap::new();
+    let options = FindOptions::builder().projection(doc! {"groupe_id": 1, "categorie_id": 1, "supprime": 1}).build();
+    let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
+    let mut curseur = collection.find_with_session(filtre.clone(), options, session).await?;
+    while let Some(row) = curseur.next(session).await {
+        let groupe: GroupeRef = convertir_bson_deserializable(row?)?;
+        if !categories.contains(&groupe.categorie_id) {
+            rapport.groupes_categorie_inconnue.push(groupe.groupe_id.clone());
+        }
+        groupes.insert(groupe.groupe_id.clone(), groupe);
+    }
+
+    let mut documents_orphelins = HashMap::new();
+    let options = FindOptions::builder()
+        .projection(doc! {"doc_id": 1, "groupe_id": 1, "categorie_version": 1, "supprime": 1, "cle_id": 1})
+        .build();
+    let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+    let mut curseur = collection.find_with_session(filtre, options, session).await?;
+    while let Some(row) = curseur.next(session).await {
+        let document: DocumentRef = convertir_bson_deserializable(row?)?;
+        match groupes.get(&document.groupe_id) {
+            Some(groupe) => {
+                if Some(true) == groupe.supprime && Some(true) != document.supprime {
+                    rapport.documents_groupe_supprime.push(document.doc_id.clone());
+                }
+                if !versions.contains(&(groupe.categorie_id.clone(), document.categorie_version)) {
+                    rapport.documents_version_inconnue.push(document.doc_id.clone());
+                }
+            },
+            None => {
+                rapport.documents_groupe_inconnu.push(document.doc_id.clone());
+                documents_orphelins.insert(document.doc_id.clone(), document);
+            }
+        }
+    }
+
+    debug!("analyser Usager {} : {} anomalies", user_id, rapport.nombre_anomalies());
+    Ok((rapport, documents_orphelins))
+}
+
+/// Applique la reparation. Appele par la transaction `reparerIntegrite`.
+pub async fn reparer_usager<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: &TransactionValide, session: &mut ClientSession)
+    -> Result<RapportIntegrite, Error>
+    where M: MongoDao
+{
+    let reparation: TransactionReparerIntegrite = match serde_json::from_str(transaction.transaction.contenu.as_str()) {
+        Ok(t) => t,
+        Err(e) => Err(format!("integrite.reparer_usager Erreur conversion transaction : {:?}", e))?
+    };
+    let user_id = reparation.user_id.as_str();
+    let (rapport, documents_orphelins) = analyser(gestionnaire, middleware, user_id, session).await?;
+
+    // Le groupe de recuperation doit exister et ne pas etre supprime au moment de la transaction.
+    let groupe_recuperation = match reparation.groupe_id_recuperation.as_ref() {
+        Some(groupe_id) => {
+            let filtre = doc! { "user_id": user_id, "groupe_id": groupe_id, "supprime": {"$ne": true} };
+            let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
+            collection.find_one_with_session(filtre, None, session).await?.map(|groupe| {
+                let cle_groupe = groupe.get_str("cle_id").or(groupe.get_str("ref_hachage_bytes")).ok().map(|c| c.to_string());
+                (groupe_id.as_str(), cle_groupe)
+            })
+        },
+        None => None
+    };
+
+    let collection_documents = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+    let mut deplaces = 0;
+    for (doc_id, document) in documents_orphelins.iter() {
+        let filtre = doc! { "user_id": user_id, "doc_id": doc_id };
+        let ops = match (groupe_recuperation.as_ref(), document.cle_id.as_ref()) {
+            // Le document est chiffre avec la cle du groupe de recuperation, il peut y etre deplace
+            (Some((groupe_id, Some(cle_groupe))), Some(cle_document)) if cle_groupe == cle_document => {
+                deplaces += 1;
+                doc! {
+                    "$set": {"groupe_id": *groupe_id, "groupe_id_origine": &document.groupe_id},
+                    "$unset": {"integrite": true},
+                    "$currentDate": {CHAMP_MODIFICATION: true},
+                }
+            },
+            _ => ops_marquer(ANOMALIE_GROUPE_INCONNU, transaction)
+        };
+        collection_documents.update_one_with_session(filtre, ops, None, session).await?;
+    }
+    if let (Some((groupe_id, _)), true) = (groupe_recuperation, deplaces > 0) {
+        maj_statistiques_groupe(gestionnaire, middleware, user_id, groupe_id, session).await?;
+    }
+
+    for doc_id in rapport.documents_version_inconnue.iter() {
+        let filtre = doc! { "user_id": user_id, "doc_id": doc_id };
+        collection_documents.update_one_with_session(filtre, ops_marquer(ANOMALIE_VERSION_INCONNUE, transaction), None, session).await?;
+    }
+
+    let collection_groupes = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
+    for groupe_id in rapport.groupes_categorie_inconnue.iter() {
+        let filtre = doc! { "user_id": user_id, "groupe_id": groupe_id };
+        collection_groupes.update_one_with_session(filtre, ops_marquer(ANOMALIE_CATEGORIE_INCONNUE, transaction), None, session).await?;
+    }
+
+    info!("reparer_usager Usager {} : {} documents deplaces, {} anomalies traitees", user_id, deplaces, rapport.nombre_anomalies());
+    Ok(rapport)
+}
+
+fn ops_marquer(anomalie: &str, transaction: &TransactionValide) -> Document {
+    doc! {
+        "$set": {
+            "integrite": {
+                "anomalie": anomalie,
+                "transaction_id": &transaction.transaction.id,
+                "date": transaction.transaction.estampille,
+            }
+        },
+    }
+}
+
+/// Conserve le rapport de l'usager. Un rapport sans anomalie est retire.
+pub async fn conserver_rapport<M>(middleware: &M, rapport: &RapportIntegrite, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_RAPPORTS_INTEGRITE)?;
+    let filtre = doc! { "user_id": &rapport.user_id };
+    if rapport.nombre_anomalies() == 0 {
+        collection.delete_one_with_session(filtre, None, session).await?;
+    } else {
+        let ops = doc! { "$set": convertir_to_bson(rapport)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection.update_one_with_session(filtre, ops, options, session).await?;
+    }
+    Ok(())
+}
+
+/// Tache planifiee : analyse tous les usagers et conserve les rapports.
+pub async fn verifier_integrite_usagers<M>(gestionnaire: &DocumentsDomainManager, middleware: &M) -> Result<(), Error>
+    where M: MongoDao
+{
+    let mut session = middleware.get_session().await?;
+    let mut user_ids = HashSet::new();
+    for nom_collection in [NOM_COLLECTION_GROUPES_USAGERS, NOM_COLLECTION_DOCUMENTS_USAGERS] {
+        let collection = middleware.get_collection(nom_collection)?;
+        for user_id in collection.distinct_with_session("user_id", doc! {}, None, &mut session).await? {
+            if let Bson::String(user_id) = user_id {
+                user_ids.insert(user_id);
+            }
+        }
+    }
+
+    let mut usagers_anomalies = 0;
+    for user_id in user_ids.iter() {
+        let rapport = analyser_usager(gestionnaire, middleware, user_id, &mut session).await?;
+        if rapport.nombre_anomalies() > 0 {
+            usagers_anomalies += 1;
+        }
+        conserver_rapport(middleware, &rapport, &mut session).await?;
+    }
+
+    info!("verifier_integrite_usagers {} usagers verifies, {} avec anomalies", user_ids.len(), usagers_anomalies);
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! Journal d'activite des usagers. Une entree est conservee pour chaque transaction appliquee
+//! avec le certificat d'un usager (appareil, fingerprint, objets touches).
+//!
+//! Une transaction d'administration (e.g. reparerIntegrite) est journalisee pour l'usager cible
+//! (`user_id` du contenu); le fingerprint, la pubkey et l'appareil restent ceux du signataire.
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::epochseconds;
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
+use millegrilles_common_rust::serde_json;
+use serde::{Deserialize, Serialize};
+
+use crate::constantes::*;
+use crate::domain_manager::DocumentsDomainManager;
+
+/// Entree du journal d'activite.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActiviteUsager {
+    pub transaction_id: String,
+    pub user_id: String,
+    pub action: String,
+    pub categorie_id: Option<String>,
+    pub groupe_id: Option<String>,
+    pub doc_id: Option<String>,
+    /// Fingerprint du certificat qui a signe la transaction.
+    pub fingerprint: String,
+    /// Cle publique du signataire (champ pubkey de la transaction), utilisee par la reconstruction pour
+    /// retrouver les transactions de l'usager. Absente des entrees anterieures.
+    pub pubkey: Option<String>,
+    /// Nom de l'appareil ou de l'application (common name du certificat).
+    pub appareil: Option<String>,
+    #[serde(serialize_with = "epochseconds::serialize", deserialize_with = "chrono_datetime_as_bson_datetime::deserialize")]
+    pub date: DateTime<Utc>,
+}
+
+/// Identifiants d'objets presents dans le contenu des transactions du domaine.
+#[derive(Deserialize)]
+struct IdentifiantsObjets {
+    /// Usager cible d'une transaction d'administration.
+    user_id: Option<String>,
+    categorie_id: Option<String>,
+    groupe_id: Option<String>,
+    doc_id: Option<String>,
+}
+
+/// Prepare l'entree de journal d'une transaction. Retourne None pour une transaction systeme (sans user_id).
+pub fn preparer_activite(transaction: &TransactionValide, action: &str) -> Result<Option<ActiviteUsager>, Error> {
+    // Les donnees de l'usager sont purgees, incluant son journal. Ne pas laisser d'entree residuelle.
+    if action == TRANSACTION_SUPPRIMER_USAGER {
+        return Ok(None)
+    }
+
+    let identifiants: IdentifiantsObjets = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match action {
+        // Transaction d'administration : journaliser pour l'usager cible
+        TRANSACTION_REPARER_INTEGRITE => identifiants.user_id.clone(),
+        _ => transaction.certificat.get_user_id()?
+    };
+    let user_id = match user_id {
+        Some(inner) => inner,
+        None => return Ok(None)
+    };
+
+    // Lors d'une creation ou d'une copie, l'identifiant du nouvel objet est l'id de la transaction.
+    let transaction_id = transaction.transaction.id.clone();
+    let (categorie_id, groupe_id, doc_id) = match action {
+        TRANSACTION_SAUVEGARDER_CATEGORIE_USAGER =>
+            (identifiants.categorie_id.or(Some(transaction_id.clone())), None, None),
+        TRANSACTION_SAUVEGARDER_GROUPE_USAGER =>
+            (identifiants.categorie_id, identifiants.groupe_id.or(Some(transaction_id.clone())), None),
+        TRANSACTION_SAUVEGARDER_DOCUMENT =>
+            (None, identifiants.groupe_id, identifiants.doc_id.or(Some(transaction_id.clone()))),
+        // Contenu : doc_id source, groupe_id destination
+        TRANSACTION_COPIER_DOCUMENT =>
+            (None, identifiants.groupe_id, Some(transaction_id.clone())),
+        // Contenu : groupe_id source. Les documents copies ont des doc_id derives de la transaction.
+        TRANSACTION_COPIER_GROUPE =>
+            (None, Some(transaction_id.clone()), None),
+        _ => (identifiants.categorie_id, identifiants.groupe_id, identifiants.doc_id)
+    };
+
+    Ok(Some(ActiviteUsager {
+        transaction_id,
+        user_id,
+        action: action.to_string(),
+        categorie_id,
+        groupe_id,
+        doc_id,
+        fingerprint: transaction.certificat.fingerprint()?,
+        pubkey: Some(transaction.transaction.pubkey.clone()),
+        appareil: transaction.certificat.get_common_name().ok(),
+        date: transaction.transaction.estampille,
+    }))
+}
+
+/// Conserve l'entree de journal. Idempotent (cle transaction_id) pour supporter la regeneration.
+pub async fn enregistrer_activite<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, activite: ActiviteUsager, session: &mut ClientSession) -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! { "transaction_id": &activite.transaction_id };
+    let ops = doc! {
+        "$setOnInsert": {
+            "transaction_id": &activite.transaction_id,
+            "user_id": activite.user_id,
+            "action": activite.action,
+            "categorie_id": activite.categorie_id,
+            "groupe_id": activite.groupe_id,
+            "doc_id": activite.doc_id,
+            "fingerprint": activite.fingerprint,
+            "pubkey": activite.pubkey,
+            "appareil": activite.appareil,
+            "date": activite.date,
+        }
+    };
+    let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_JOURNAL_ACTIVITE).as_str())?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one_with_session(filtre, ops, options, session).await?;
+    Ok(())
+}
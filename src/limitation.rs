@@ -0,0 +1,198 @@
+//! Limitation du debit des commandes (token bucket) par usager et par certificat.
+//!
+//! Chaque commande consomme un jeton dans le seau de l'usager et dans celui du certificat, pour la
+//! classe de l'action. La commande est refusee si un des seaux est vide, avec le delai avant le
+//! prochain jeton. L'etat est conserve en memoire : avec plusieurs instances du domaine, les limites
+//! s'appliquent par instance.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::configuration::ConfigurationDocuments;
+use crate::constantes::*;
+
+/// Au-dela de ce nombre de seaux, les seaux pleins (inactifs) sont retires.
+const CONST_SEAUX_MAX: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClasseAction {
+    /// Sauvegarde, suppression et recuperation.
+    Ecriture,
+    /// Copie de documents et de groupes.
+    Copie,
+    /// Commandes d'administration (non limitees).
+    Administration,
+}
+
+impl ClasseAction {
+    pub fn charger(action: &str) -> Self {
+        match action {
+            TRANSACTION_COPIER_DOCUMENT | TRANSACTION_COPIER_GROUPE => ClasseAction::Copie,
+            COMMANDE_EFFACER_DONNEES_USAGER | COMMANDE_SAUVEGARDER_QUOTA_USAGER |
+            TRANSACTION_REPARER_INTEGRITE | COMMANDE_RECONSTRUIRE_USAGER => ClasseAction::Administration,
+            _ => ClasseAction::Ecriture
+        }
+    }
+}
+
+/// Parametres d'un seau : nombre de jetons maximal et recharge par minute.
+#[derive(Clone, Copy, Debug)]
+pub struct ParametresSeau {
+    pub capacite: u32,
+    pub recharge_par_minute: u32,
+}
+
+impl ParametresSeau {
+    fn recharge_par_seconde(&self) -> f64 {
+        self.recharge_par_minute as f64 / 60.0
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Seau {
+    jetons: f64,
+    derniere_maj: Instant,
+}
+
+impl Seau {
+    fn recharger(&mut self, parametres: &ParametresSeau, maintenant: Instant) {
+        let ecoule = maintenant.saturating_duration_since(self.derniere_maj).as_secs_f64();
+        self.jetons = (self.jetons + ecoule * parametres.recharge_par_seconde()).min(parametres.capacite as f64);
+        self.derniere_maj = maintenant;
+    }
+
+    /// Delai avant qu'un jeton soit disponible.
+    fn delai(&self, parametres: &ParametresSeau) -> Duration {
+        let manquant = 1.0 - self.jetons;
+        if manquant <= 0.0 {
+            Duration::ZERO
+        } else if parametres.recharge_par_minute == 0 {
+            Duration::MAX
+        } else {
+            Duration::from_secs_f64(manquant / parametres.recharge_par_seconde())
+        }
+    }
+}
+
+pub struct LimiteurCommandes {
+    ecriture: ParametresSeau,
+    copie: ParametresSeau,
+    seaux: Mutex<HashMap<(String, ClasseAction), Seau>>,
+}
+
+impl LimiteurCommandes {
+    pub fn new(configuration: &ConfigurationDocuments) -> Self {
+        LimiteurCommandes {
+            ecriture: configuration.limite_ecriture,
+            copie: configuration.limite_copie,
+            seaux: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn parametres(&self, classe: ClasseAction) -> Option<&ParametresSeau> {
+        match classe {
+            ClasseAction::Ecriture => Some(&self.ecriture),
+            ClasseAction::Copie => Some(&self.copie),
+            ClasseAction::Administration => None,
+        }
+    }
+
+    /// Consomme un jeton pour chaque cle (e.g. usager et certificat). Aucun jeton n'est consomme
+    /// si une des cles est limitee. Retourne le delai avant de reessayer en cas de refus.
+    pub fn verifier(&self, cles: &[String], classe: ClasseAction, maintenant: Instant) -> Result<(), Duration> {
+        let parametres = match self.parametres(classe) {
+            Some(inner) => inner,
+            None => return Ok(())
+        };
+
+        let mut seaux = self.seaux.lock().expect("lock seaux");
+        if seaux.len() > CONST_SEAUX_MAX {
+            retirer_seaux_pleins(&mut seaux, self, maintenant);
+        }
+
+        let mut delai = Duration::ZERO;
+        for cle in cles {
+            let seau = seaux.entry((cle.clone(), classe))
+                .or_insert_with(|| Seau { jetons: parametres.capacite as f64, derniere_maj: maintenant });
+            seau.recharger(parametres, maintenant);
+            delai = delai.max(seau.delai(parametres));
+        }
+        if delai > Duration::ZERO {
+            return Err(delai)
+        }
+
+        for cle in cles {
+            if let Some(seau) = seaux.get_mut(&(cle.clone(), classe)) {
+                seau.jetons -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn retirer_seaux_pleins(seaux: &mut HashMap<(String, ClasseAction), Seau>, limiteur: &LimiteurCommandes, maintenant: Instant) {
+    seaux.retain(|(_, classe), seau| {
+        match limiteur.parametres(*classe) {
+            Some(parametres) => {
+                seau.recharger(parametres, maintenant);
+                seau.jetons < parametres.capacite as f64
+            },
+            None => false
+        }
+    });
+}
+
+#[cfg(test)]
+mod test_limitation {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn limiteur() -> LimiteurCommandes {
+        let mut configuration = ConfigurationDocuments::default();
+        configuration.limite_ecriture = ParametresSeau { capacite: 2, recharge_par_minute: 60 };
+        LimiteurCommandes::new(&configuration)
+    }
+
+    #[test]
+    fn test_seau_vide() {
+        setup("test_seau_vide");
+        let limiteur = limiteur();
+        let cles = ["usager".to_string(), "certificat".to_string()];
+        let maintenant = Instant::now();
+        assert!(limiteur.verifier(&cles, ClasseAction::Ecriture, maintenant).is_ok());
+        assert!(limiteur.verifier(&cles, ClasseAction::Ecriture, maintenant).is_ok());
+        let delai = limiteur.verifier(&cles, ClasseAction::Ecriture, maintenant).unwrap_err();
+        assert!(delai <= Duration::from_secs(1));
+
+        // Recharge d'un jeton par seconde
+        let plus_tard = maintenant + Duration::from_secs(1);
+        assert!(limiteur.verifier(&cles, ClasseAction::Ecriture, plus_tard).is_ok());
+    }
+
+    #[test]
+    fn test_cle_limitee_ne_consomme_pas() {
+        setup("test_cle_limitee_ne_consomme_pas");
+        let limiteur = limiteur();
+        let maintenant = Instant::now();
+        let cert_1 = ["usager".to_string(), "cert_1".to_string()];
+        let cert_2 = ["usager".to_string(), "cert_2".to_string()];
+        assert!(limiteur.verifier(&cert_1, ClasseAction::Ecriture, maintenant).is_ok());
+        assert!(limiteur.verifier(&cert_2, ClasseAction::Ecriture, maintenant).is_ok());
+        // L'usager est limite, peu importe le certificat
+        assert!(limiteur.verifier(&cert_2, ClasseAction::Ecriture, maintenant).is_err());
+        // Le seau du certificat n'a pas ete consomme par le refus
+        assert!(limiteur.verifier(&["cert_2".to_string()], ClasseAction::Ecriture, maintenant).is_ok());
+    }
+
+    #[test]
+    fn test_administration_non_limitee() {
+        setup("test_administration_non_limitee");
+        let limiteur = limiteur();
+        let cles = ["admin".to_string()];
+        let maintenant = Instant::now();
+        for _ in 0..10 {
+            assert!(limiteur.verifier(&cles, ClasseAction::charger(COMMANDE_EFFACER_DONNEES_USAGER), maintenant).is_ok());
+        }
+    }
+}
@@ -5,9 +5,23 @@ mod constantes;
 mod requetes;
 mod transactions;
 mod evenements;
+mod evenements_maj;
 mod common;
 mod builder;
 mod domain_manager;
+mod configuration;
+mod autorisation;
+mod journal;
+mod validation;
+mod effacement;
+mod quotas;
+mod limitation;
+mod verification_cles;
+mod taches;
+mod integrite;
+mod reconstruction;
+mod statut;
+mod arret;
 
 // use crate::domaine::run;
 use crate::builder::run;
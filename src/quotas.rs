@@ -0,0 +1,238 @@
+//! Quotas de stockage par usager (nombre de documents et taille totale de data_chiffre).
+//!
+//! Les limites par defaut viennent de la configuration. Un administrateur peut les remplacer pour un
+//! usager dans `Documents/quotasUsagers`. Les documents supprimes (corbeille) sont comptes puisqu'ils
+//! occupent toujours de l'espace.
+//!
+//! L'utilisation est un compteur (`utilisation`) conserve sur la meme ligne et incremente par les commandes
+//! dans leur transaction Mongo. Deux commandes concurrentes du meme usager ecrivent la meme ligne : l'une
+//! des deux echoue (write conflict) au lieu de depasser le quota. Les traitements qui modifient les
+//! documents sans passer par `reserver_quota_usager` (migration, reparation, reconstruction) retirent le
+//! compteur, qui est recalcule par agregation a la prochaine reservation.
+
+use millegrilles_common_rust::bson::{doc, Bson, Document};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::ConfigurationDocuments;
+use crate::constantes::*;
+
+/// Limites applicables a un usager.
+#[derive(Clone, Debug, Serialize)]
+pub struct QuotaUsager {
+    pub nombre_documents: i64,
+    pub taille_chiffre: i64,
+}
+
+/// Remplacement des limites par defaut et compteur d'utilisation pour un usager.
+#[derive(Clone, Debug, Deserialize)]
+struct DocQuotaUsager {
+    nombre_documents: Option<i64>,
+    taille_chiffre: Option<i64>,
+    /// Absent si le compteur doit etre recalcule.
+    utilisation: Option<UtilisationUsager>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UtilisationUsager {
+    #[serde(default)]
+    pub nombre_documents: i64,
+    #[serde(default)]
+    pub taille_chiffre: i64,
+}
+
+#[derive(Clone, Debug)]
+pub enum ErreurQuota {
+    NombreDocuments,
+    TailleChiffre,
+}
+
+impl ErreurQuota {
+    pub fn code(&self) -> usize {
+        ERREUR_QUOTA_EXCEDE
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErreurQuota::NombreDocuments => "Document count quota exceeded",
+            ErreurQuota::TailleChiffre => "Storage quota exceeded",
+        }
+    }
+}
+
+async fn charger_doc_quota<M>(middleware: &M, user_id: &str, session: &mut ClientSession)
+    -> Result<Option<DocQuotaUsager>, Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_QUOTAS_USAGERS)?;
+    match collection.find_one_with_session(doc! { "user_id": user_id }, None, session).await? {
+        Some(row) => Ok(Some(convertir_bson_deserializable(row)?)),
+        None => Ok(None)
+    }
+}
+
+fn quota_applicable(configuration: &ConfigurationDocuments, doc_quota: Option<&DocQuotaUsager>) -> QuotaUsager {
+    let mut quota = QuotaUsager {
+        nombre_documents: configuration.quota_nombre_documents,
+        taille_chiffre: configuration.quota_taille_chiffre,
+    };
+    if let Some(remplacement) = doc_quota {
+        if let Some(nombre) = remplacement.nombre_documents {
+            quota.nombre_documents = nombre;
+        }
+        if let Some(taille) = remplacement.taille_chiffre {
+            quota.taille_chiffre = taille;
+        }
+    }
+    quota
+}
+
+/// Charge les limites de l'usager (remplacement ou valeurs par defaut) et son utilisation.
+/// L'utilisation est calculee par agregation si le compteur est absent.
+pub async fn charger_quota<M>(middleware: &M, configuration: &ConfigurationDocuments, user_id: &str, session: &mut ClientSession)
+    -> Result<(QuotaUsager, UtilisationUsager), Error>
+    where M: MongoDao
+{
+    let doc_quota = charger_doc_quota(middleware, user_id, session).await?;
+    let quota = quota_applicable(configuration, doc_quota.as_ref());
+    let utilisation = match doc_quota.and_then(|d| d.utilisation) {
+        Some(inner) => inner,
+        None => calculer_utilisation(middleware, doc! { "user_id": user_id }, session).await?
+    };
+    Ok((quota, utilisation))
+}
+
+/// Calcule l'utilisation des documents correspondant au filtre (doit inclure user_id).
+pub async fn calculer_utilisation<M>(middleware: &M, filtre: Document, session: &mut ClientSession)
+    -> Result<UtilisationUsager, Error>
+    where M: MongoDao
+{
+    let pipeline = vec![
+        doc! { "$match": filtre },
+        doc! { "$group": {
+            "_id": Bson::Null,
+            "nombre_documents": {"$sum": 1},
+            "taille_chiffre": {"$sum": {"$strLenBytes": {"$ifNull": ["$data_chiffre", ""]}}},
+        }},
+    ];
+
+    let collection = middleware.get_collection(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+    let mut curseur = collection.aggregate_with_session(pipeline, None, session).await?;
+    match curseur.next(session).await {
+        Some(row) => Ok(convertir_bson_deserializable(row?)?),
+        None => Ok(UtilisationUsager::default())
+    }
+}
+
+/// Verifie qu'un ajout (delta) respecte le quota. Une reduction est toujours acceptee.
+pub fn verifier_quota(quota: &QuotaUsager, utilisation: &UtilisationUsager, delta_nombre: i64, delta_taille: i64)
+    -> Result<(), ErreurQuota>
+{
+    if delta_nombre > 0 && utilisation.nombre_documents + delta_nombre > quota.nombre_documents {
+        Err(ErreurQuota::NombreDocuments)?
+    }
+    if delta_taille > 0 && utilisation.taille_chiffre + delta_taille > quota.taille_chiffre {
+        Err(ErreurQuota::TailleChiffre)?
+    }
+    Ok(())
+}
+
+/// Verifie le quota de l'usager et reserve l'ajout (delta) dans le compteur d'utilisation.
+/// Doit etre appele apres les autres validations de la commande, dans la meme session que la transaction.
+pub async fn reserver_quota_usager<M>(
+    middleware: &M, configuration: &ConfigurationDocuments, user_id: &str,
+    delta_nombre: i64, delta_taille: i64, session: &mut ClientSession
+)
+    -> Result<Result<(), ErreurQuota>, Error>
+    where M: MongoDao
+{
+    let doc_quota = charger_doc_quota(middleware, user_id, session).await?;
+    let quota = quota_applicable(configuration, doc_quota.as_ref());
+    let compteur = doc_quota.and_then(|d| d.utilisation);
+    let utilisation = match compteur.as_ref() {
+        Some(inner) => inner.clone(),
+        None => calculer_utilisation(middleware, doc! { "user_id": user_id }, session).await?
+    };
+
+    if let Err(e) = verifier_quota(&quota, &utilisation, delta_nombre, delta_taille) {
+        return Ok(Err(e))
+    }
+
+    let ops = match compteur {
+        Some(_) => doc! {
+            "$inc": {
+                "utilisation.nombre_documents": delta_nombre,
+                "utilisation.taille_chiffre": delta_taille,
+            }
+        },
+        None => doc! {
+            "$set": {
+                "utilisation": {
+                    "nombre_documents": utilisation.nombre_documents + delta_nombre,
+                    "taille_chiffre": utilisation.taille_chiffre + delta_taille,
+                }
+            }
+        }
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_QUOTAS_USAGERS)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one_with_session(doc! { "user_id": user_id }, ops, options, session).await?;
+
+    Ok(Ok(()))
+}
+
+/// Retire le compteur d'utilisation de l'usager. Il sera recalcule a la prochaine reservation.
+pub async fn invalider_utilisation<M>(middleware: &M, user_id: &str, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_QUOTAS_USAGERS)?;
+    let ops = doc! { "$unset": { "utilisation": true } };
+    collection.update_one_with_session(doc! { "user_id": user_id }, ops, None, session).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_quotas {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn quota() -> QuotaUsager {
+        QuotaUsager { nombre_documents: 10, taille_chiffre: 1000 }
+    }
+
+    #[test]
+    fn test_quota_respecte() {
+        setup("test_quota_respecte");
+        let utilisation = UtilisationUsager { nombre_documents: 9, taille_chiffre: 900 };
+        assert!(verifier_quota(&quota(), &utilisation, 1, 100).is_ok());
+    }
+
+    #[test]
+    fn test_quota_excede() {
+        setup("test_quota_excede");
+        let utilisation = UtilisationUsager { nombre_documents: 10, taille_chiffre: 900 };
+        assert!(matches!(verifier_quota(&quota(), &utilisation, 1, 0), Err(ErreurQuota::NombreDocuments)));
+        assert!(matches!(verifier_quota(&quota(), &utilisation, 0, 101), Err(ErreurQuota::TailleChiffre)));
+    }
+
+    #[test]
+    fn test_quota_remplacement_partiel() {
+        setup("test_quota_remplacement_partiel");
+        let configuration = ConfigurationDocuments::default();
+        let doc_quota = DocQuotaUsager { nombre_documents: Some(5), taille_chiffre: None, utilisation: None };
+        let quota = quota_applicable(&configuration, Some(&doc_quota));
+        assert_eq!(5, quota.nombre_documents);
+        assert_eq!(configuration.quota_taille_chiffre, quota.taille_chiffre);
+    }
+
+    #[test]
+    fn test_quota_reduction_acceptee() {
+        setup("test_quota_reduction_acceptee");
+        let utilisation = UtilisationUsager { nombre_documents: 20, taille_chiffre: 5000 };
+        assert!(verifier_quota(&quota(), &utilisation, 0, -100).is_ok());
+    }
+}
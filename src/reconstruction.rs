@@ -0,0 +1,476 @@
+//! Reconstruction des projections d'un usager a partir de ses transactions.
+//!
+//! Les transactions de l'usager (signees par un de ses certificats, ou transactions d'administration
+//! qui le visent) sont rejouees dans l'ordre avec `aiguillage_transaction` vers des collections
+//! temporaires. Les collections temporaires sont comparees aux projections courantes de l'usager.
+//! Sur demande, les projections de l'usager sont remplacees dans la transaction de la commande. Les
+//! collections temporaires sont toujours retirees a la fin.
+//!
+//! Les certificats (pubkey) de l'usager proviennent de son journal d'activite. Seules les transactions
+//! completees signees par un de ces certificats ou d'administration sont lues (index
+//! `transactions_pubkey_complete` et `transactions_action_complete`). Les entrees de journal anterieures
+//! a l'ajout de la pubkey sont completees par une regeneration du domaine.
+//!
+//! Le reste du domaine continue de fonctionner pendant la reconstruction. Avec le remplacement, les
+//! commandes de l'usager sont refusees jusqu'au commit. Une commande commencee avant le blocage est
+//! detectee en recomptant les transactions de l'usager avant le remplacement, qui est alors annule.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::bson::{doc, Bson, Document};
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::constantes::{CHAMP_CREATION, CHAMP_MODIFICATION, TRANSACTION_CHAMP_COMPLETE};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use millegrilles_common_rust::transactions::charger_transaction;
+use serde::{Deserialize, Serialize};
+
+use crate::constantes::*;
+use crate::domain_manager::DocumentsDomainManager;
+use crate::quotas::invalider_utilisation;
+use crate::transactions::aiguillage_transaction;
+
+/// Collections reconstruites et champs qui identifient un objet de l'usager.
+const COLLECTIONS_RECONSTRUCTION: [(&str, &[&str]); 5] = [
+    (NOM_COLLECTION_CATEGORIES_USAGERS, &["categorie_id"]),
+    (NOM_COLLECTION_CATEGORIES_USAGERS_VERSION, &["categorie_id", "version"]),
+    (NOM_COLLECTION_GROUPES_USAGERS, &["groupe_id"]),
+    (NOM_COLLECTION_DOCUMENTS_USAGERS, &["doc_id"]),
+    (NOM_COLLECTION_JOURNAL_ACTIVITE, &["transaction_id"]),
+];
+
+/// Champs qui dependent de la date d'application ou de l'entretien, ignores dans la comparaison.
+const CHAMPS_IGNORES: [&str; 5] = ["_id", CHAMP_CREATION, CHAMP_MODIFICATION, "cle_inconnue", "cle_verification_date"];
+
+/// Transactions d'administration qui visent un usager par le champ user_id de leur contenu.
+const TRANSACTIONS_ADMINISTRATION: [&str; 2] = [TRANSACTION_SUPPRIMER_USAGER, TRANSACTION_REPARER_INTEGRITE];
+
+/// Nombre maximal d'identifiants listes par type de difference.
+const CONST_DIFFERENCES_MAX: usize = 100;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommandeReconstruireUsager {
+    pub user_id: String,
+    /// Remplacer les projections courantes par les projections reconstruites.
+    pub appliquer: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DifferencesCollection {
+    pub collection: String,
+    pub nombre_courant: usize,
+    pub nombre_reconstruit: usize,
+    /// Objets presents dans la projection courante seulement.
+    pub absents: Vec<String>,
+    /// Objets presents dans la projection reconstruite seulement.
+    pub ajoutes: Vec<String>,
+    /// Objets dont le contenu differe.
+    pub differents: Vec<String>,
+}
+
+impl DifferencesCollection {
+    fn est_identique(&self) -> bool {
+        self.absents.is_empty() && self.ajoutes.is_empty() && self.differents.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RapportReconstruction {
+    pub ok: bool,
+    pub user_id: String,
+    pub reconstruction_id: String,
+    pub transactions_appliquees: usize,
+    pub transactions_en_erreur: Vec<String>,
+    pub collections: Vec<DifferencesCollection>,
+    pub identique: bool,
+    pub applique: bool,
+}
+
+#[derive(Deserialize)]
+struct RoutageRef {
+    action: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TransactionRef {
+    id: String,
+    contenu: String,
+    routage: Option<RoutageRef>,
+}
+
+#[derive(Deserialize)]
+struct ContenuUsager {
+    user_id: Option<String>,
+}
+
+#[derive(Default)]
+struct TransactionsRejouees {
+    /// Transactions retournees par le filtre, incluant les transactions d'administration d'autres usagers.
+    lues: u64,
+    appliquees: usize,
+    en_erreur: Vec<String>,
+}
+
+/// Usagers dont les commandes sont refusees pendant une reconstruction avec remplacement.
+#[derive(Debug, Default)]
+pub struct UsagersBloques {
+    usagers: Mutex<HashSet<String>>,
+}
+
+/// Blocage d'un usager, retire lorsqu'il est droppe.
+pub struct BlocageUsager<'a> {
+    usagers: &'a UsagersBloques,
+    user_id: String,
+}
+
+impl Drop for BlocageUsager<'_> {
+    fn drop(&mut self) {
+        self.usagers.usagers.lock().expect("lock usagers").remove(&self.user_id);
+    }
+}
+
+impl UsagersBloques {
+    /// Bloque l'usager. Retourne None si l'usager est deja bloque (reconstruction en cours).
+    pub fn bloquer(&self, user_id: &str) -> Option<BlocageUsager<'_>> {
+        if !self.usagers.lock().expect("lock usagers").insert(user_id.to_string()) {
+            return None
+        }
+        Some(BlocageUsager { usagers: self, user_id: user_id.to_string() })
+    }
+
+    pub fn est_bloque(&self, user_id: &str) -> bool {
+        self.usagers.lock().expect("lock usagers").contains(user_id)
+    }
+}
+
+/// Reconstruit les projections de l'usager. Le remplacement (optionnel) utilise la session de la commande.
+pub async fn reconstruire_usager<M>(
+    gestionnaire: &DocumentsDomainManager, middleware: &M, commande: &CommandeReconstruireUsager,
+    reconstruction_id: &str, session: &mut ClientSession
+)
+    -> Result<RapportReconstruction, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let gestionnaire_reconstruction = gestionnaire.pour_reconstruction(reconstruction_id);
+    let resultat = reconstruire(gestionnaire, &gestionnaire_reconstruction, middleware, commande, reconstruction_id, session).await;
+
+    // Toujours retirer les collections temporaires
+    for (nom_collection, _) in COLLECTIONS_RECONSTRUCTION {
+        let nom_temporaire = gestionnaire_reconstruction.nom_collection(nom_collection);
+        let collection = middleware.get_collection(nom_temporaire.as_str())?;
+        if let Err(e) = collection.drop(None).await {
+            warn!("reconstruire_usager Erreur retrait collection temporaire {} : {:?}", nom_temporaire, e);
+        }
+    }
+
+    resultat
+}
+
+async fn reconstruire<M>(
+    gestionnaire: &DocumentsDomainManager, gestionnaire_reconstruction: &DocumentsDomainManager, middleware: &M,
+    commande: &CommandeReconstruireUsager, reconstruction_id: &str, session: &mut ClientSession
+)
+    -> Result<RapportReconstruction, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let user_id = commande.user_id.as_str();
+    info!("reconstruire Debut reconstruction {} de l'usager {}", reconstruction_id, user_id);
+
+    // Refuser les commandes de l'usager jusqu'au remplacement (commit de la commande)
+    let _blocage = match commande.appliquer {
+        Some(true) => match gestionnaire.usagers_bloques.bloquer(user_id) {
+            Some(inner) => Some(inner),
+            None => Err(format!("reconstruire Reconstruction deja en cours pour l'usager {}", user_id))?
+        },
+        _ => None
+    };
+
+    let pubkeys = charger_pubkeys_usager(middleware, user_id).await?;
+
+    // Les collections temporaires ne sont pas dans la transaction de la commande
+    let mut session_reconstruction = middleware.get_session().await?;
+    let TransactionsRejouees { lues, appliquees: transactions_appliquees, en_erreur: transactions_en_erreur } = rejouer_transactions(
+        gestionnaire_reconstruction, middleware, user_id, &pubkeys, &mut session_reconstruction).await?;
+
+    let mut collections = Vec::new();
+    let mut projections = Vec::new();
+    for (nom_collection, cles) in COLLECTIONS_RECONSTRUCTION {
+        let courants = charger_documents(middleware, nom_collection, user_id, session).await?;
+        let nom_temporaire = gestionnaire_reconstruction.nom_collection(nom_collection);
+        let reconstruits = charger_documents(middleware, nom_temporaire.as_str(), user_id, &mut session_reconstruction).await?;
+        collections.push(comparer(nom_collection, cles, &courants, &reconstruits));
+        projections.push((nom_collection, reconstruits));
+    }
+    let identique = collections.iter().all(|c| c.est_identique());
+
+    let applique = commande.appliquer == Some(true) && !identique;
+    if applique {
+        // Une commande de l'usager commencee avant le blocage a pu ajouter une transaction
+        verifier_version_transactions(middleware, &pubkeys, lues, session).await?;
+        remplacer_projections(gestionnaire, middleware, user_id, projections, session).await?;
+    }
+
+    info!("reconstruire Fin reconstruction {} de l'usager {} : {} transactions, {} en erreur, identique {}, applique {}",
+        reconstruction_id, user_id, transactions_appliquees, transactions_en_erreur.len(), identique, applique);
+
+    Ok(RapportReconstruction {
+        ok: true,
+        user_id: user_id.to_string(),
+        reconstruction_id: reconstruction_id.to_string(),
+        transactions_appliquees,
+        transactions_en_erreur,
+        collections,
+        identique,
+        applique,
+    })
+}
+
+/// Filtre des transactions completees de l'usager : signees par un de ses certificats (pubkey) ou
+/// d'administration. Le user_id des transactions d'administration est dans le contenu, il est verifie
+/// au moment de rejouer.
+pub fn filtre_transactions_usager(pubkeys: &[String]) -> Document {
+    doc! {
+        TRANSACTION_CHAMP_COMPLETE: true,
+        "$or": [
+            {"pubkey": {"$in": pubkeys.to_vec()}},
+            {"routage.action": {"$in": TRANSACTIONS_ADMINISTRATION.to_vec()}},
+        ]
+    }
+}
+
+/// Charge les certificats (pubkey) de l'usager a partir de son journal d'activite (index
+/// `journal_usager_date`). Les entrees des transactions d'administration portent la pubkey du signataire,
+/// elles sont ignorees.
+async fn charger_pubkeys_usager<M>(middleware: &M, user_id: &str) -> Result<Vec<String>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        "user_id": user_id,
+        "action": {"$nin": TRANSACTIONS_ADMINISTRATION.to_vec()},
+        "pubkey": {"$exists": true},
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_JOURNAL_ACTIVITE)?;
+    let pubkeys = collection.distinct("pubkey", filtre, None).await?.into_iter()
+        .filter_map(|pubkey| match pubkey {
+            Bson::String(inner) => Some(inner),
+            _ => None
+        })
+        .collect();
+    Ok(pubkeys)
+}
+
+/// Rejoue les transactions completees de l'usager dans l'ordre.
+async fn rejouer_transactions<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, user_id: &str, pubkeys: &[String], session: &mut ClientSession)
+    -> Result<TransactionsRejouees, Error>
+    where M: ValidateurX509 + GenerateurMessages + MongoDao
+{
+    let options = FindOptions::builder()
+        .projection(doc! {"id": 1, "contenu": 1, "routage": 1})
+        .sort(doc! {"estampille": 1})
+        .build();
+    let collection = middleware.get_collection_typed::<TransactionRef>(NOM_COLLECTION_TRANSACTIONS)?;
+    let mut curseur = collection.find(filtre_transactions_usager(pubkeys), options).await?;
+
+    let mut resultat = TransactionsRejouees::default();
+    while let Some(row) = curseur.next().await {
+        let transaction_ref = row?;
+        resultat.lues += 1;
+        let action = transaction_ref.routage.as_ref().and_then(|r| r.action.as_deref());
+
+        // Transaction d'administration, verifier l'usager vise
+        if action.map(|a| TRANSACTIONS_ADMINISTRATION.contains(&a)).unwrap_or(false) {
+            let contenu: ContenuUsager = match serde_json::from_str(transaction_ref.contenu.as_str()) {
+                Ok(inner) => inner,
+                Err(_) => continue
+            };
+            if contenu.user_id.as_deref() != Some(user_id) {
+                continue
+            }
+        }
+
+        let transaction = match charger_transaction(middleware, NOM_COLLECTION_TRANSACTIONS, transaction_ref.id.as_str()).await {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("rejouer_transactions Erreur chargement transaction {} : {:?}", transaction_ref.id, e);
+                resultat.en_erreur.push(transaction_ref.id);
+                continue
+            }
+        };
+
+        debug!("rejouer_transactions Transaction {} ({:?})", transaction_ref.id, action);
+        match aiguillage_transaction(gestionnaire, middleware, transaction, session).await {
+            Ok(_) => resultat.appliquees += 1,
+            Err(e) => {
+                warn!("rejouer_transactions Erreur transaction {} : {:?}", transaction_ref.id, e);
+                resultat.en_erreur.push(transaction_ref.id);
+            }
+        }
+    }
+
+    Ok(resultat)
+}
+
+/// Verifie qu'aucune transaction de l'usager n'a ete ajoutee depuis la lecture pour la reconstruction.
+async fn verifier_version_transactions<M>(middleware: &M, pubkeys: &[String], lues: u64, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_TRANSACTIONS)?;
+    let courant = collection.count_documents_with_session(filtre_transactions_usager(pubkeys), None, session).await?;
+    if courant != lues {
+        Err(format!("verifier_version_transactions Transactions ajoutees pendant la reconstruction ({} lues, {} courantes), remplacement annule", lues, courant))?
+    }
+    Ok(())
+}
+
+async fn charger_documents<M>(middleware: &M, nom_collection: &str, user_id: &str, session: &mut ClientSession)
+    -> Result<Vec<Document>, Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(nom_collection)?;
+    let mut curseur = collection.find_with_session(doc! { "user_id": user_id }, None, session).await?;
+    let mut documents = Vec::new();
+    while let Some(row) = curseur.next(session).await {
+        documents.push(row?);
+    }
+    Ok(documents)
+}
+
+/// Remplace les projections courantes de l'usager par les projections reconstruites.
+async fn remplacer_projections<M>(
+    gestionnaire: &DocumentsDomainManager, middleware: &M, user_id: &str,
+    projections: Vec<(&str, Vec<Document>)>, session: &mut ClientSession
+)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! { "user_id": user_id };
+    for (nom_collection, documents) in projections {
+        let collection = middleware.get_collection(gestionnaire.nom_collection(nom_collection).as_str())?;
+        let resultat = collection.delete_many_with_session(filtre.clone(), None, session).await?;
+        debug!("remplacer_projections {} documents retires de {}", resultat.deleted_count, nom_collection);
+        if documents.is_empty() {
+            continue
+        }
+        let documents: Vec<Document> = documents.into_iter()
+            .map(|mut d| { d.remove("_id"); d })
+            .collect();
+        collection.insert_many_with_session(documents, None, session).await?;
+    }
+    invalider_utilisation(middleware, user_id, session).await?;
+    Ok(())
+}
+
+fn cle_objet(cles: &[&str], document: &Document) -> String {
+    cles.iter()
+        .map(|c| match document.get(c) {
+            Some(Bson::String(inner)) => inner.clone(),
+            Some(inner) => inner.to_string(),
+            None => String::new()
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn normaliser(document: &Document) -> Document {
+    let mut document = document.clone();
+    for champ in CHAMPS_IGNORES {
+        document.remove(champ);
+    }
+    if let Ok(statistiques) = document.get_document_mut("statistiques") {
+        statistiques.remove("derniere_modification_document");
+    }
+    document
+}
+
+fn comparer(nom_collection: &str, cles: &[&str], courants: &[Document], reconstruits: &[Document]) -> DifferencesCollection {
+    let courants: HashMap<String, Document> = courants.iter()
+        .map(|d| (cle_objet(cles, d), normaliser(d)))
+        .collect();
+    let reconstruits: HashMap<String, Document> = reconstruits.iter()
+        .map(|d| (cle_objet(cles, d), normaliser(d)))
+        .collect();
+
+    let mut differences = DifferencesCollection {
+        collection: nom_collection.to_string(),
+        nombre_courant: courants.len(),
+        nombre_reconstruit: reconstruits.len(),
+        ..Default::default()
+    };
+
+    let identifiants: HashSet<&String> = courants.keys().chain(reconstruits.keys()).collect();
+    let mut identifiants: Vec<&String> = identifiants.into_iter().collect();
+    identifiants.sort();
+    for identifiant in identifiants {
+        let liste = match (courants.get(identifiant), reconstruits.get(identifiant)) {
+            (Some(_), None) => &mut differences.absents,
+            (None, Some(_)) => &mut differences.ajoutes,
+            (Some(courant), Some(reconstruit)) if courant != reconstruit => &mut differences.differents,
+            _ => continue
+        };
+        if liste.len() < CONST_DIFFERENCES_MAX {
+            liste.push(identifiant.clone());
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod test_reconstruction {
+    use super::*;
+    use crate::test_setup::setup;
+
+    #[test]
+    fn test_comparer_identique() {
+        setup("test_comparer_identique");
+        let courants = [doc! {"_id": 1, "doc_id": "DOC1", "user_id": "U", CHAMP_MODIFICATION: 10}];
+        let reconstruits = [doc! {"_id": 2, "doc_id": "DOC1", "user_id": "U", CHAMP_MODIFICATION: 20}];
+        let differences = comparer(NOM_COLLECTION_DOCUMENTS_USAGERS, &["doc_id"], &courants, &reconstruits);
+        assert!(differences.est_identique());
+    }
+
+    #[test]
+    fn test_comparer_differences() {
+        setup("test_comparer_differences");
+        let courants = [
+            doc! {"doc_id": "DOC1", "data_chiffre": "a"},
+            doc! {"doc_id": "DOC2", "data_chiffre": "b"},
+        ];
+        let reconstruits = [
+            doc! {"doc_id": "DOC1", "data_chiffre": "c"},
+            doc! {"doc_id": "DOC3", "data_chiffre": "d"},
+        ];
+        let differences = comparer(NOM_COLLECTION_DOCUMENTS_USAGERS, &["doc_id"], &courants, &reconstruits);
+        assert_eq!(vec!["DOC2".to_string()], differences.absents);
+        assert_eq!(vec!["DOC3".to_string()], differences.ajoutes);
+        assert_eq!(vec!["DOC1".to_string()], differences.differents);
+    }
+
+    #[test]
+    fn test_usager_bloque() {
+        setup("test_usager_bloque");
+        let usagers = UsagersBloques::default();
+        {
+            let _blocage = usagers.bloquer("U1").expect("blocage");
+            assert!(usagers.est_bloque("U1"));
+            assert!(!usagers.est_bloque("U2"));
+            assert!(usagers.bloquer("U1").is_none());
+        }
+        assert!(!usagers.est_bloque("U1"));
+    }
+
+    #[test]
+    fn test_cle_objet_composee() {
+        setup("test_cle_objet_composee");
+        let version = doc! {"categorie_id": "CAT1", "version": 2};
+        assert_eq!("CAT1/2", cle_objet(&["categorie_id", "version"], &version));
+    }
+}
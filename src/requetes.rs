@@ -1,15 +1,16 @@
 use std::collections::HashMap;
 use log::{debug, error};
 
-use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::{doc, Bson, Document};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
-use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
 use millegrilles_common_rust::common_messages::RequeteDechiffrage;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction, RoutageMessageReponse};
 use millegrilles_common_rust::get_domaine_action;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{MessageMilleGrillesBufferAlloc, MessageMilleGrillesBufferDefault};
-use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, opt_chrono_datetime_as_bson_datetime, MongoDao};
+use millegrilles_common_rust::mongodb::options::FindOptions;
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::recepteur_messages::MessageValide;
 use millegrilles_common_rust::serde::{Deserialize, Serialize};
@@ -22,9 +23,15 @@ use millegrilles_common_rust::millegrilles_cryptographie::deser_message_buffer;
 use millegrilles_common_rust::millegrilles_cryptographie::chiffrage::formatchiffragestr;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, optionepochseconds};
 
-use crate::common::{DocCategorieUsager, DocDocument, DocGroupeUsager};
+use crate::autorisation::{verifier_autorisation, POLITIQUES_REQUETES};
+use crate::common::{DocCategorieUsager, DocDocument, DocGroupeUsager, StatistiquesGroupe};
 use crate::constantes::*;
 use crate::domain_manager::DocumentsDomainManager;
+use crate::integrite::{analyser_usager, conserver_rapport, RapportIntegrite};
+use crate::journal::ActiviteUsager;
+use crate::taches::{charger_statut, StatutTache, TACHES};
+use crate::statut::{verifier_mongo, EtatMongo, EtatVerification, CONST_FRAICHEUR_CERTIFICATS_MINUTES, CONST_FRAICHEUR_REDIS_MINUTES};
+use crate::quotas::{charger_quota, QuotaUsager, UtilisationUsager};
 
 pub async fn consommer_requete<M>(middleware: &M, message: MessageValide, gestionnaire: &DocumentsDomainManager)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -32,29 +39,28 @@ pub async fn consommer_requete<M>(middleware: &M, message: MessageValide, gestio
 {
     debug!("Consommer requete : {:?}", &message.type_message);
 
-    let user_id = message.certificat.get_user_id()?;
-    let role_prive = message.certificat.verifier_roles(vec![RolesCertificats::ComptePrive])?;
-
-    if role_prive && user_id.is_some() {
-        // Ok, commande usager
-    } else if message.certificat.verifier_exchanges(vec![Securite::L2Prive, Securite::L3Protege])? {
-        // Autorisation : On accepte les requetes de 3.protege ou 4.secure
-        // Ok
-    } else if message.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
-        // Ok
-    } else {
-        Err(format!("consommer_requete autorisation invalide (pas d'un exchange reconnu)"))?
-    }
-
     let (domaine, action) = get_domaine_action!(message.type_message);
 
+    // Autorisation selon la politique de l'action
+    verifier_autorisation(&POLITIQUES_REQUETES, action.as_str(), message.certificat.as_ref())?;
+
     match domaine.as_str() {
         DOMAINE_NOM => {
             match action.as_str() {
                 REQUETE_CATEGORIES_USAGER => requete_get_categories_usager(middleware, message, gestionnaire).await,
                 REQUETE_GROUPES_USAGER => requete_get_groupes_usager(middleware, message, gestionnaire).await,
                 REQUETE_GROUPES_CLES => requete_get_groupes_cles(middleware, message, gestionnaire).await,
+                REQUETE_DOCUMENTS_CLES => requete_get_documents_cles(middleware, message, gestionnaire).await,
                 REQUETE_DOCUMENTS_GROUPE => requete_get_documents_groupe(middleware, message, gestionnaire).await,
+                REQUETE_CAPACITES => requete_get_capacites(middleware, message, gestionnaire).await,
+                REQUETE_JOURNAL_ACTIVITE => requete_get_journal_activite(middleware, message, gestionnaire).await,
+                REQUETE_UTILISATION => requete_get_utilisation(middleware, message, gestionnaire).await,
+                REQUETE_GROUPES_CLES_INCONNUES => requete_get_groupes_cles_inconnues(middleware, message, gestionnaire).await,
+                REQUETE_CHIFFRAGE_OBSOLETE => requete_get_chiffrage_obsolete(middleware, message, gestionnaire).await,
+                REQUETE_STATISTIQUES_USAGE => requete_get_statistiques_usage(middleware, message, gestionnaire).await,
+                REQUETE_STATUT_TACHES => requete_get_statut_taches(middleware, message, gestionnaire).await,
+                REQUETE_RAPPORT_INTEGRITE => requete_get_rapport_integrite(middleware, message, gestionnaire).await,
+                REQUETE_STATUT => requete_get_statut(middleware, message, gestionnaire).await,
                 _ => {
                     error!("Message requete/action inconnue : '{}'. Message dropped.", action);
                     Ok(None)
@@ -114,16 +120,100 @@ async fn requete_get_categories_usager<M>(middleware: &M, m: MessageValide, gest
     Ok(Some(middleware.build_reponse(&reponse)?.0))
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Mode de projection pour les requetes de liste. Permet d'eviter de charger le contenu chiffre.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ModeProjection {
+    /// Identifiants seulement
+    Ids,
+    /// Identifiants, version, cle_id et dates
+    Meta,
+    /// Document complet, incluant data_chiffre (defaut)
+    Complet,
+}
+
+impl Default for ModeProjection {
+    fn default() -> Self {
+        ModeProjection::Complet
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
 struct RequeteGetGroupesUsager {
     limit: Option<i32>,
     skip: Option<i32>,
     supprime: Option<bool>,
+    #[serde(default)]
+    projection: ModeProjection,
+}
+
+/// Groupe sans contenu chiffre (projection ids ou meta).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DocGroupeUsagerProjection {
+    groupe_id: String,
+    categorie_id: String,
+    supprime: Option<bool>,
+    cle_id: Option<String>,
+    format: Option<String>,
+    #[serde(rename = "_mg-creation", default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    creation: Option<DateTime<Utc>>,
+    #[serde(rename = "_mg-derniere-modification", default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    derniere_modification: Option<DateTime<Utc>>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    supprime_date: Option<DateTime<Utc>>,
+    statistiques: Option<StatistiquesGroupe>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum GroupeReponse {
+    Complet(DocGroupeUsager),
+    Projection(DocGroupeUsagerProjection),
+}
+
+impl GroupeReponse {
+    fn charger(mode: ModeProjection, row: Document) -> Result<Self, Error> {
+        Ok(match mode {
+            ModeProjection::Complet => GroupeReponse::Complet(convertir_bson_deserializable(row)?),
+            _ => GroupeReponse::Projection(convertir_bson_deserializable(row)?)
+        })
+    }
+
+    fn groupe_id(self) -> String {
+        match self {
+            GroupeReponse::Complet(inner) => inner.groupe_id,
+            GroupeReponse::Projection(inner) => inner.groupe_id,
+        }
+    }
+
+    fn supprime(&self) -> bool {
+        match self {
+            GroupeReponse::Complet(inner) => inner.supprime == Some(true),
+            GroupeReponse::Projection(inner) => inner.supprime == Some(true),
+        }
+    }
+}
+
+fn projection_groupes(mode: ModeProjection) -> Option<Document> {
+    match mode {
+        ModeProjection::Ids => Some(doc! {"groupe_id": 1, "categorie_id": 1, "supprime": 1}),
+        ModeProjection::Meta => Some(doc! {
+            "groupe_id": 1, "categorie_id": 1, "supprime": 1, "cle_id": 1, "format": 1,
+            CHAMP_CREATION: 1, CHAMP_MODIFICATION: 1, NOM_CHAMP_SUPPRIME_DATE: 1, "statistiques": 1,
+        }),
+        ModeProjection::Complet => None,
+    }
 }
 
 #[derive(Serialize)]
 struct ReponseGetGroupes {
-    groupes: Vec<DocGroupeUsager>,
+    groupes: Vec<GroupeReponse>,
     supprimes: Vec<String>,
     #[serde(serialize_with = "epochseconds::serialize")]
     date_sync: DateTime<Utc>,
@@ -158,19 +248,22 @@ async fn requete_get_groupes_usager<M>(middleware: &M, m: MessageValide, gestion
         let mut liste_supprimes = Vec::new();
 
         let filtre = doc! { "user_id": &user_id };
+        let options = FindOptions::builder()
+            .projection(projection_groupes(requete.projection))
+            .build();
         let collection = middleware.get_collection(NOM_COLLECTION_GROUPES_USAGERS)?;
 
-        let mut curseur = collection.find(filtre, None).await?;
+        let mut curseur = collection.find(filtre, options).await?;
         while let Some(doc_groupe) = curseur.next().await {
-            let groupe: DocGroupeUsager = convertir_bson_deserializable(doc_groupe?)?;
+            let groupe = GroupeReponse::charger(requete.projection, doc_groupe?)?;
 
             if supprime_only {
-                if Some(true) == groupe.supprime {
+                if groupe.supprime() {
                     liste_groupes.push(groupe);
                 }
             } else {
-                if Some(true) == groupe.supprime {
-                    liste_supprimes.push(groupe.groupe_id);
+                if groupe.supprime() {
+                    liste_supprimes.push(groupe.groupe_id());
                 } else {
                     liste_groupes.push(groupe);
                 }
@@ -262,19 +355,27 @@ async fn requete_get_groupes_cles<M>(middleware: &M, m: MessageValide, gestionna
         cle_ids.push(cle_id);
     }
 
-    let (reply_to, correlation_id) = match m.type_message {
+    transmettre_requete_cles(middleware, m.type_message, certificat_client, cle_ids).await
+}
+
+/// Transmet une requete de dechiffrage a MaitreDesCles. La reponse est acheminee directement au client.
+async fn transmettre_requete_cles<M>(middleware: &M, type_message: TypeMessageOut, certificat_client: Vec<String>, cle_ids: Vec<String>)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages
+{
+    let (reply_to, correlation_id) = match type_message {
         TypeMessageOut::Requete(r) => {
             let reply_to = match r.reply_to {
                 Some(inner) => inner,
-                None => Err(Error::Str("requete_get_groupes_cles Pas de reply_to, skip"))?
+                None => Err(Error::Str("transmettre_requete_cles Pas de reply_to, skip"))?
             };
             let correlation_id = match r.correlation_id {
                 Some(inner) => inner,
-                None => Err(Error::Str("requete_get_groupes_cles Pas de correlation_id, skip"))?
+                None => Err(Error::Str("transmettre_requete_cles Pas de correlation_id, skip"))?
             };
             (reply_to, correlation_id)
         },
-        _ => Err(Error::Str("requete_get_groupes_cles Mauvais type de message, doit etre requete"))?
+        _ => Err(Error::Str("transmettre_requete_cles Mauvais type de message, doit etre requete"))?
     };
 
     // Creer nouvelle requete pour MaitreDesCles, rediriger vers client
@@ -299,6 +400,44 @@ async fn requete_get_groupes_cles<M>(middleware: &M, m: MessageValide, gestionna
     Ok(None)
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct RequeteGetDocumentsCles {
+    cle_ids: Vec<String>
+}
+
+/// Cles propres aux documents de l'usager. Seuls les cle_ids de documents appartenant a l'usager
+/// sont transmis a MaitreDesCles.
+async fn requete_get_documents_cles<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_documents_cles Message : {:?}", & m.type_message);
+    let requete: RequeteGetDocumentsCles = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(u) => u,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Access denied"))?))
+    };
+
+    let certificat_client = m.certificat.chaine_pem()?;
+
+    let filtre = doc! { "user_id": &user_id, "cle_id": {"$in": &requete.cle_ids} };
+    let collection = middleware.get_collection(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+    let cle_ids: Vec<String> = collection.distinct("cle_id", filtre, None).await?
+        .into_iter()
+        .filter_map(|v| match v {
+            Bson::String(inner) => Some(inner),
+            _ => None
+        })
+        .collect();
+
+    if cle_ids.is_empty() {
+        return Ok(Some(middleware.reponse_err(404, None, Some("No keys for user documents"))?))
+    }
+
+    transmettre_requete_cles(middleware, m.type_message, certificat_client, cle_ids).await
+}
+
 
 #[derive(Deserialize)]
 struct RequeteGetDocumentsGroupe {
@@ -310,11 +449,86 @@ struct RequeteGetDocumentsGroupe {
     #[serde(default, deserialize_with = "optionepochseconds::deserialize")]
     date_sync: Option<DateTime<Utc>>,
     stream: Option<bool>,
+    #[serde(default)]
+    projection: ModeProjection,
+}
+
+/// Document sans contenu chiffre (projection ids ou meta).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DocDocumentProjection {
+    doc_id: String,
+    groupe_id: String,
+    supprime: Option<bool>,
+    categorie_version: Option<i32>,
+    cle_id: Option<String>,
+    format: Option<String>,
+    compression: Option<String>,
+    #[serde(rename = "_mg-creation", default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    creation: Option<DateTime<Utc>>,
+    #[serde(rename = "_mg-derniere-modification", default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    derniere_modification: Option<DateTime<Utc>>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    supprime_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum DocumentReponse {
+    Complet(DocDocument),
+    Projection(DocDocumentProjection),
+}
+
+impl DocumentReponse {
+    fn charger(mode: ModeProjection, row: Document) -> Result<Self, Error> {
+        Ok(match mode {
+            ModeProjection::Complet => DocumentReponse::Complet(convertir_bson_deserializable(row)?),
+            _ => DocumentReponse::Projection(convertir_bson_deserializable(row)?)
+        })
+    }
+
+    fn doc_id(self) -> String {
+        match self {
+            DocumentReponse::Complet(inner) => inner.doc_id,
+            DocumentReponse::Projection(inner) => inner.doc_id,
+        }
+    }
+
+    fn supprime(&self) -> bool {
+        match self {
+            DocumentReponse::Complet(inner) => inner.supprime == Some(true),
+            DocumentReponse::Projection(inner) => inner.supprime == Some(true),
+        }
+    }
+
+    /// Taille approximative du document dans la reponse.
+    fn taille(&self) -> usize {
+        match self {
+            DocumentReponse::Complet(inner) => inner.data_chiffre.len() + CONST_DOCUMENT_META_LEN,
+            DocumentReponse::Projection(_) => CONST_DOCUMENT_META_LEN,
+        }
+    }
+}
+
+fn projection_documents(mode: ModeProjection) -> Option<Document> {
+    match mode {
+        ModeProjection::Ids => Some(doc! {"doc_id": 1, "groupe_id": 1, "supprime": 1}),
+        ModeProjection::Meta => Some(doc! {
+            "doc_id": 1, "groupe_id": 1, "supprime": 1, "categorie_version": 1, "cle_id": 1, "format": 1,
+            "compression": 1, CHAMP_CREATION: 1, CHAMP_MODIFICATION: 1, NOM_CHAMP_SUPPRIME_DATE: 1,
+        }),
+        ModeProjection::Complet => None,
+    }
 }
 
 #[derive(Serialize)]
 struct ReponseGetDocumentsGroupe<'a> {
-    documents: &'a Vec<DocDocument>,
+    documents: &'a Vec<DocumentReponse>,
     supprimes: &'a Vec<String>,
     #[serde(serialize_with = "epochseconds::serialize")]
     date_sync: &'a DateTime<Utc>,
@@ -385,15 +599,18 @@ async fn requete_get_documents_groupe<M>(middleware: &M, m: MessageValide, gesti
                 }
             }
         };
-        let collection = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+        let options = FindOptions::builder()
+            .projection(projection_documents(requete.projection))
+            .build();
+        let collection = middleware.get_collection(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
 
-        let mut curseur = collection.find(filtre, None).await?;
-        while curseur.advance().await? {
-            let doc = curseur.deserialize_current()?;
+        let mut curseur = collection.find(filtre, options).await?;
+        while let Some(row) = curseur.next().await {
+            let doc = DocumentReponse::charger(requete.projection, row?)?;
 
             // Compteur de taille de reponse:
             // data_chiffre plus padding de bytes approximant le reste du message
-            taille_documents += doc.data_chiffre.len() + CONST_DOCUMENT_META_LEN;
+            taille_documents += doc.taille();
 
             if stream_response && !liste_documents.is_empty() && taille_documents > CONST_STREAMING_BATCH_LEN {
                 // On a depasse la limite de streaming, emettre le document immediatement
@@ -414,19 +631,19 @@ async fn requete_get_documents_groupe<M>(middleware: &M, m: MessageValide, gesti
                 middleware.emettre_message(TypeMessageOut::Reponse(routage_reponse.clone()), reponse).await?;
 
                 // Reset liste et compteur (a taille du document courant)
-                taille_documents = doc.data_chiffre.len() + CONST_DOCUMENT_META_LEN;
+                taille_documents = doc.taille();
                 liste_documents.clear();
                 liste_supprimes.clear();
             }
 
             if supprime_only {
-                if Some(true) == doc.supprime {
+                if doc.supprime() {
                     liste_documents.push(doc);
                 }
             } else {
                 // Separer documents supprimes de documents actifs
-                if Some(true) == doc.supprime {
-                    liste_supprimes.push(doc.doc_id);
+                if doc.supprime() {
+                    liste_supprimes.push(doc.doc_id());
                 } else {
                     liste_documents.push(doc);
                 }
@@ -452,3 +669,550 @@ async fn requete_get_documents_groupe<M>(middleware: &M, m: MessageValide, gesti
         Ok(Some(reponse))
     }
 }
+
+#[derive(Serialize)]
+struct ReponseGetCapacites {
+    ok: bool,
+    version: &'static str,
+    formats_chiffrage: Vec<&'static str>,
+    compressions: Vec<&'static str>,
+    taille_max_document: usize,
+    streaming_batch_len: usize,
+    fonctionnalites: Vec<&'static str>,
+}
+
+/// Decrit les capacites et limites du serveur. Permet a un client de s'adapter a la version deployee.
+async fn requete_get_capacites<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_capacites Message : {:?}", m.type_message);
+
+    let reponse = ReponseGetCapacites {
+        ok: true,
+        version: env!("CARGO_PKG_VERSION"),
+        formats_chiffrage: CONST_FORMATS_CHIFFRAGE_SUPPORTES.to_vec(),
+        compressions: CONST_COMPRESSIONS_SUPPORTEES.to_vec(),
+        taille_max_document: gestionnaire.configuration.taille_max_document,
+        streaming_batch_len: CONST_STREAMING_BATCH_LEN,
+        fonctionnalites: CONST_FONCTIONNALITES.to_vec(),
+    };
+
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct ReponseGetUtilisation {
+    ok: bool,
+    utilisation: UtilisationUsager,
+    quota: QuotaUsager,
+}
+
+/// Utilisation de l'usager et quota applicable.
+async fn requete_get_utilisation<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_utilisation Message : {:?}", m.type_message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(u) => u,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Access denied"))?))
+    };
+
+    // Session sans transaction, lecture seulement
+    let mut session = middleware.get_session().await?;
+    let (quota, utilisation) = charger_quota(middleware, &gestionnaire.configuration, &user_id, &mut session).await?;
+
+    let reponse = ReponseGetUtilisation { ok: true, utilisation, quota };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Serialize, Deserialize)]
+struct GroupeCleInconnue {
+    groupe_id: String,
+    categorie_id: String,
+    cle_id: Option<String>,
+    ref_hachage_bytes: Option<String>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    cle_verification_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetGroupesClesInconnues {
+    ok: bool,
+    groupes: Vec<GroupeCleInconnue>,
+}
+
+/// Groupes de l'usager dont la cle n'est pas connue de MaitreDesCles (contenu irrecuperable).
+async fn requete_get_groupes_cles_inconnues<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_groupes_cles_inconnues Message : {:?}", m.type_message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(u) => u,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Access denied"))?))
+    };
+
+    let filtre = doc! { "user_id": &user_id, "cle_inconnue": true };
+    let collection = middleware.get_collection_typed::<GroupeCleInconnue>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut groupes = Vec::new();
+    while let Some(row) = curseur.next().await {
+        groupes.push(row?);
+    }
+
+    let reponse = ReponseGetGroupesClesInconnues { ok: true, groupes };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteGetChiffrageObsolete {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GroupeChiffrageObsolete {
+    groupe_id: String,
+    categorie_id: String,
+    cle_id: Option<String>,
+    ref_hachage_bytes: Option<String>,
+    header: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DocumentChiffrageObsolete {
+    doc_id: String,
+    groupe_id: String,
+    cle_id: Option<String>,
+    header: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetChiffrageObsolete {
+    ok: bool,
+    groupes: Vec<GroupeChiffrageObsolete>,
+    documents: Vec<DocumentChiffrageObsolete>,
+    nombre_groupes: u64,
+    nombre_documents: u64,
+    groupes_migres: u64,
+    documents_migres: u64,
+}
+
+/// Groupes et documents de l'usager qui utilisent encore l'ancien format de chiffrage (header ou
+/// ref_hachage_bytes sans cle_id). Les documents doivent etre migres avant leur groupe.
+async fn requete_get_chiffrage_obsolete<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_chiffrage_obsolete Message : {:?}", m.type_message);
+    let requete: RequeteGetChiffrageObsolete = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(u) => u,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Access denied"))?))
+    };
+
+    let limit = match requete.limit {
+        Some(l) if l <= 0 => return Ok(Some(middleware.reponse_err(None, None, Some("Invalid limit"))?)),
+        Some(l) => l.min(CONST_CHIFFRAGE_OBSOLETE_LIMITE_MAX),
+        None => CONST_CHIFFRAGE_OBSOLETE_LIMITE_DEFAUT
+    };
+    let filtre_obsolete = doc! {
+        "user_id": &user_id,
+        "$or": [{"header": {"$ne": null}}, {"cle_id": null}],
+    };
+    let filtre_migre = doc! { "user_id": &user_id, "migration_chiffrage": {"$exists": true} };
+
+    let collection_groupes = middleware.get_collection_typed::<GroupeChiffrageObsolete>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let nombre_groupes = collection_groupes.count_documents(filtre_obsolete.clone(), None).await?;
+    let groupes_migres = collection_groupes.count_documents(filtre_migre.clone(), None).await?;
+    let options = FindOptions::builder()
+        .projection(doc! {"groupe_id": 1, "categorie_id": 1, "cle_id": 1, "ref_hachage_bytes": 1, "header": 1})
+        .limit(limit)
+        .build();
+    let mut curseur = collection_groupes.find(filtre_obsolete.clone(), options).await?;
+    let mut groupes = Vec::new();
+    while let Some(row) = curseur.next().await {
+        groupes.push(row?);
+    }
+
+    let collection_documents = middleware.get_collection_typed::<DocumentChiffrageObsolete>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+    let nombre_documents = collection_documents.count_documents(filtre_obsolete.clone(), None).await?;
+    let documents_migres = collection_documents.count_documents(filtre_migre, None).await?;
+    let options = FindOptions::builder()
+        .projection(doc! {"doc_id": 1, "groupe_id": 1, "cle_id": 1, "header": 1})
+        .limit(limit)
+        .build();
+    let mut curseur = collection_documents.find(filtre_obsolete, options).await?;
+    let mut documents = Vec::new();
+    while let Some(row) = curseur.next().await {
+        documents.push(row?);
+    }
+
+    let reponse = ReponseGetChiffrageObsolete {
+        ok: true, groupes, documents, nombre_groupes, nombre_documents, groupes_migres, documents_migres,
+    };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteGetJournalActivite {
+    limit: Option<i64>,
+    skip: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetJournalActivite {
+    ok: bool,
+    activites: Vec<ActiviteUsager>,
+}
+
+/// Retourne le journal d'activite de l'usager, du plus recent au plus ancien.
+async fn requete_get_journal_activite<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_journal_activite Message : {:?}", m.type_message);
+    let requete: RequeteGetJournalActivite = deser_message_buffer!(m.message);
+
+    let user_id = match m.certificat.get_user_id()? {
+        Some(u) => u,
+        None => return Ok(Some(middleware.reponse_err(None, None, Some("Access denied"))?))
+    };
+
+    // Mongo traite une limite de 0 comme "sans limite" et une limite negative comme un seul batch
+    let limit = match requete.limit {
+        Some(l) => l.clamp(1, CONST_JOURNAL_LIMITE_MAX),
+        None => CONST_JOURNAL_LIMITE_DEFAUT
+    };
+    let skip = match requete.skip {
+        Some(s) if s < 0 => return Ok(Some(middleware.reponse_err(None, None, Some("Invalid skip"))?)),
+        Some(s) => s as u64,
+        None => 0
+    };
+
+    let filtre = doc! { "user_id": &user_id };
+    let options = FindOptions::builder()
+        .sort(doc! { "date": -1, "transaction_id": 1 })
+        .skip(skip)
+        .limit(limit)
+        .build();
+    let collection = middleware.get_collection_typed::<ActiviteUsager>(NOM_COLLECTION_JOURNAL_ACTIVITE)?;
+    let mut curseur = collection.find(filtre, options).await?;
+
+    let mut activites = Vec::new();
+    while let Some(row) = curseur.next().await {
+        activites.push(row?);
+    }
+
+    let reponse = ReponseGetJournalActivite { ok: true, activites };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteGetStatistiquesUsage {
+    /// Limiter le rapport a un usager
+    user_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct StatistiquesUsage {
+    nombre_categories: i64,
+    nombre_groupes: i64,
+    nombre_documents: i64,
+    nombre_documents_supprimes: i64,
+    taille_chiffre: i64,
+}
+
+impl StatistiquesUsage {
+    fn additionner(&mut self, autre: &StatistiquesUsage) {
+        self.nombre_categories += autre.nombre_categories;
+        self.nombre_groupes += autre.nombre_groupes;
+        self.nombre_documents += autre.nombre_documents;
+        self.nombre_documents_supprimes += autre.nombre_documents_supprimes;
+        self.taille_chiffre += autre.taille_chiffre;
+    }
+}
+
+#[derive(Serialize)]
+struct StatistiquesUsager {
+    user_id: String,
+    #[serde(flatten)]
+    statistiques: StatistiquesUsage,
+}
+
+#[derive(Serialize)]
+struct ReponseGetStatistiquesUsage {
+    ok: bool,
+    usagers: Vec<StatistiquesUsager>,
+    totaux: StatistiquesUsage,
+}
+
+/// Resultat d'un $group par user_id.
+#[derive(Deserialize)]
+struct AgregationUsager {
+    #[serde(rename = "_id")]
+    user_id: Option<String>,
+    nombre: Option<i64>,
+    nombre_supprimes: Option<i64>,
+    taille: Option<i64>,
+}
+
+async fn agreger_par_usager<M>(middleware: &M, nom_collection: &str, filtre: &Document, groupe: Document)
+    -> Result<Vec<AgregationUsager>, Error>
+    where M: MongoDao
+{
+    let mut groupe_ops = doc! { "_id": "$user_id" };
+    groupe_ops.extend(groupe);
+    let pipeline = vec![
+        doc! { "$match": filtre.clone() },
+        doc! { "$group": groupe_ops },
+    ];
+
+    let collection = middleware.get_collection(nom_collection)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut resultat = Vec::new();
+    while let Some(row) = curseur.next().await {
+        resultat.push(convertir_bson_deserializable(row?)?);
+    }
+    Ok(resultat)
+}
+
+/// Statistiques d'utilisation par usager et pour l'instance (administration).
+async fn requete_get_statistiques_usage<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_statistiques_usage Message : {:?}", m.type_message);
+    let requete: RequeteGetStatistiquesUsage = deser_message_buffer!(m.message);
+
+    let filtre = match requete.user_id.as_ref() {
+        Some(user_id) => doc! { "user_id": user_id },
+        None => doc! {}
+    };
+
+    let mut usagers: HashMap<String, StatistiquesUsage> = HashMap::new();
+
+    let compte = doc! { "nombre": {"$sum": 1} };
+    for ligne in agreger_par_usager(middleware, NOM_COLLECTION_CATEGORIES_USAGERS, &filtre, compte.clone()).await? {
+        if let Some(user_id) = ligne.user_id {
+            usagers.entry(user_id).or_default().nombre_categories = ligne.nombre.unwrap_or(0);
+        }
+    }
+    for ligne in agreger_par_usager(middleware, NOM_COLLECTION_GROUPES_USAGERS, &filtre, compte).await? {
+        if let Some(user_id) = ligne.user_id {
+            usagers.entry(user_id).or_default().nombre_groupes = ligne.nombre.unwrap_or(0);
+        }
+    }
+
+    let groupe_documents = doc! {
+        "nombre": {"$sum": {"$cond": [{"$eq": ["$supprime", true]}, 0, 1]}},
+        "nombre_supprimes": {"$sum": {"$cond": [{"$eq": ["$supprime", true]}, 1, 0]}},
+        "taille": {"$sum": {"$strLenBytes": {"$ifNull": ["$data_chiffre", ""]}}},
+    };
+    for ligne in agreger_par_usager(middleware, NOM_COLLECTION_DOCUMENTS_USAGERS, &filtre, groupe_documents).await? {
+        if let Some(user_id) = ligne.user_id {
+            let statistiques = usagers.entry(user_id).or_default();
+            statistiques.nombre_documents = ligne.nombre.unwrap_or(0);
+            statistiques.nombre_documents_supprimes = ligne.nombre_supprimes.unwrap_or(0);
+            statistiques.taille_chiffre = ligne.taille.unwrap_or(0);
+        }
+    }
+
+    let mut totaux = StatistiquesUsage::default();
+    let mut liste_usagers = Vec::with_capacity(usagers.len());
+    for (user_id, statistiques) in usagers {
+        totaux.additionner(&statistiques);
+        liste_usagers.push(StatistiquesUsager { user_id, statistiques });
+    }
+    liste_usagers.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+    let reponse = ReponseGetStatistiquesUsage { ok: true, usagers: liste_usagers, totaux };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct StatutTacheReponse {
+    nom: &'static str,
+    intervalle_minutes: i64,
+    minute: u32,
+    statut: Option<StatutTache>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetStatutTaches {
+    ok: bool,
+    taches: Vec<StatutTacheReponse>,
+}
+
+/// Planification et resultat de la derniere execution des taches d'entretien (administration).
+async fn requete_get_statut_taches<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_statut_taches Message : {:?}", m.type_message);
+
+    let mut taches = Vec::new();
+    for tache in TACHES.iter() {
+        taches.push(StatutTacheReponse {
+            nom: tache.nom,
+            intervalle_minutes: tache.intervalle_minutes,
+            minute: tache.minute,
+            statut: charger_statut(middleware, tache.nom).await?,
+        });
+    }
+
+    let reponse = ReponseGetStatutTaches { ok: true, taches };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RequeteGetRapportIntegrite {
+    /// Usager a verifier. Sans usager, retourne les rapports conserves avec anomalies.
+    user_id: Option<String>,
+    /// Refaire l'analyse de l'usager plutot que retourner le dernier rapport.
+    rafraichir: Option<bool>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetRapportIntegrite {
+    ok: bool,
+    rapports: Vec<RapportIntegrite>,
+}
+
+/// Rapports d'integrite referentielle (administration).
+async fn requete_get_rapport_integrite<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_rapport_integrite Message : {:?}", m.type_message);
+    let requete: RequeteGetRapportIntegrite = deser_message_buffer!(m.message);
+
+    let limit = match requete.limit {
+        Some(l) if l <= 0 => return Ok(Some(middleware.reponse_err(None, None, Some("Invalid limit"))?)),
+        Some(l) => l.min(CONST_RAPPORTS_INTEGRITE_LIMITE_MAX),
+        None => CONST_RAPPORTS_INTEGRITE_LIMITE_DEFAUT
+    };
+
+    let mut rapports = Vec::new();
+    let collection = middleware.get_collection_typed::<RapportIntegrite>(NOM_COLLECTION_RAPPORTS_INTEGRITE)?;
+    match requete.user_id.as_ref() {
+        Some(user_id) => {
+            if requete.rafraichir == Some(true) {
+                let mut session = middleware.get_session().await?;
+                let rapport = analyser_usager(gestionnaire, middleware, user_id, &mut session).await?;
+                conserver_rapport(middleware, &rapport, &mut session).await?;
+                rapports.push(rapport);
+            } else if let Some(rapport) = collection.find_one(doc! { "user_id": user_id }, None).await? {
+                rapports.push(rapport);
+            }
+        },
+        None => {
+            let options = FindOptions::builder()
+                .sort(doc! {"date": -1})
+                .limit(limit)
+                .build();
+            let mut curseur = collection.find(doc! {}, options).await?;
+            while let Some(row) = curseur.next().await {
+                rapports.push(row?);
+            }
+        }
+    }
+
+    let reponse = ReponseGetRapportIntegrite { ok: true, rapports };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct EtatCertificatsMaitredescles {
+    present: bool,
+    frais: bool,
+    nombre: u64,
+    verification: EtatVerification,
+}
+
+#[derive(Serialize)]
+struct EtatRedis {
+    /// Redis configure et verifie au moins une fois par l'entretien.
+    verifie: bool,
+    disponible: bool,
+    verification: Option<EtatVerification>,
+}
+
+#[derive(Serialize)]
+struct ReponseGetStatut {
+    ok: bool,
+    instance_id: String,
+    #[serde(with = "epochseconds")]
+    date_demarrage: DateTime<Utc>,
+    uptime_secondes: u64,
+    certificats_maitredescles: EtatCertificatsMaitredescles,
+    mongo: EtatMongo,
+    redis: EtatRedis,
+    taches: Vec<StatutTacheReponse>,
+    commandes_traitees: u64,
+    commandes_en_erreur: u64,
+}
+
+/// Etat de l'instance pour les operateurs (administration).
+async fn requete_get_statut<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_get_statut Message : {:?}", m.type_message);
+    let statut = gestionnaire.statut.as_ref();
+    let maintenant = Utc::now();
+
+    let (nombre, verification) = statut.certificats_maitredescles();
+    let certificats_maitredescles = EtatCertificatsMaitredescles {
+        present: nombre > 0,
+        frais: verification.est_frais(&maintenant, Duration::minutes(CONST_FRAICHEUR_CERTIFICATS_MINUTES)),
+        nombre,
+        verification,
+    };
+
+    let redis = match statut.redis() {
+        Some(verification) => EtatRedis {
+            verifie: true,
+            disponible: verification.est_frais(&maintenant, Duration::minutes(CONST_FRAICHEUR_REDIS_MINUTES)),
+            verification: Some(verification),
+        },
+        None => EtatRedis { verifie: false, disponible: false, verification: None }
+    };
+
+    let mongo = verifier_mongo(middleware, statut).await;
+
+    // Les taches sont dans Mongo, ne pas echouer la requete si Mongo n'est pas disponible
+    let mut taches = Vec::new();
+    if mongo.disponible {
+        for tache in TACHES.iter() {
+            taches.push(StatutTacheReponse {
+                nom: tache.nom,
+                intervalle_minutes: tache.intervalle_minutes,
+                minute: tache.minute,
+                statut: charger_statut(middleware, tache.nom).await.unwrap_or(None),
+            });
+        }
+    }
+
+    let (commandes_traitees, commandes_en_erreur) = statut.commandes();
+    let reponse = ReponseGetStatut {
+        ok: true,
+        instance_id: gestionnaire.instance_id.clone(),
+        date_demarrage: statut.date_demarrage,
+        uptime_secondes: statut.uptime_secondes(),
+        certificats_maitredescles,
+        mongo,
+        redis,
+        taches,
+        commandes_traitees,
+        commandes_en_erreur,
+    };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
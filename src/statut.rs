@@ -0,0 +1,230 @@
+//! Etat du service pour les operateurs (requete `getStatut`).
+//!
+//! Les compteurs de commandes et le resultat des verifications faites par `thread_entretien`
+//! (certificats de MaitreDesCles, redis, transactions en attente) sont conserves en memoire depuis le
+//! demarrage de l'instance. Mongo est verifie (ping) au moment de la requete.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::TRANSACTION_CHAMP_COMPLETE;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::optionepochseconds;
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::options::CountOptions;
+use serde::Serialize;
+
+use crate::constantes::*;
+
+/// Delai apres lequel une verification reussie n'est plus consideree fraiche.
+pub const CONST_FRAICHEUR_CERTIFICATS_MINUTES: i64 = 15;
+pub const CONST_FRAICHEUR_REDIS_MINUTES: i64 = 15;
+/// Borne du compte des transactions en attente. Une valeur egale a la borne signifie "au moins".
+pub const CONST_LIMITE_TRANSACTIONS_EN_ATTENTE: u64 = 1000;
+
+/// Resultat de la derniere verification d'une dependance.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EtatVerification {
+    #[serde(serialize_with = "optionepochseconds::serialize")]
+    pub dernier_succes: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "optionepochseconds::serialize")]
+    pub derniere_erreur_date: Option<DateTime<Utc>>,
+    pub derniere_erreur: Option<String>,
+}
+
+impl EtatVerification {
+    fn maj(&mut self, resultat: Result<(), String>, date: DateTime<Utc>) {
+        match resultat {
+            Ok(()) => self.dernier_succes = Some(date),
+            Err(e) => {
+                self.derniere_erreur_date = Some(date);
+                self.derniere_erreur = Some(e);
+            }
+        }
+    }
+
+    /// Le dernier succes est recent et n'a pas ete suivi d'une erreur.
+    pub fn est_frais(&self, maintenant: &DateTime<Utc>, delai: Duration) -> bool {
+        match self.dernier_succes.as_ref() {
+            Some(succes) => {
+                let erreur_posterieure = self.derniere_erreur_date.as_ref().map(|e| e > succes).unwrap_or(false);
+                *maintenant - *succes <= delai && !erreur_posterieure
+            },
+            None => false
+        }
+    }
+}
+
+pub struct StatutService {
+    pub date_demarrage: DateTime<Utc>,
+    demarrage: Instant,
+    commandes_traitees: AtomicU64,
+    commandes_en_erreur: AtomicU64,
+    nombre_certificats_maitredescles: AtomicU64,
+    certificats_maitredescles: Mutex<EtatVerification>,
+    redis: Mutex<Option<EtatVerification>>,
+    transactions_en_attente: Mutex<Option<(u64, DateTime<Utc>)>>,
+}
+
+impl StatutService {
+    pub fn new() -> Self {
+        StatutService {
+            date_demarrage: Utc::now(),
+            demarrage: Instant::now(),
+            commandes_traitees: AtomicU64::new(0),
+            commandes_en_erreur: AtomicU64::new(0),
+            nombre_certificats_maitredescles: AtomicU64::new(0),
+            certificats_maitredescles: Mutex::new(EtatVerification::default()),
+            redis: Mutex::new(None),
+            transactions_en_attente: Mutex::new(None),
+        }
+    }
+
+    pub fn uptime_secondes(&self) -> u64 {
+        self.demarrage.elapsed().as_secs()
+    }
+
+    /// Compte une commande traitee. Une commande en erreur est aussi comptee comme traitee.
+    pub fn commande_traitee(&self, succes: bool) {
+        self.commandes_traitees.fetch_add(1, Ordering::Relaxed);
+        if !succes {
+            self.commandes_en_erreur.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn commandes(&self) -> (u64, u64) {
+        (self.commandes_traitees.load(Ordering::Relaxed), self.commandes_en_erreur.load(Ordering::Relaxed))
+    }
+
+    pub fn maj_certificats_maitredescles(&self, resultat: Result<usize, String>) {
+        let resultat = resultat.map(|nombre| {
+            self.nombre_certificats_maitredescles.store(nombre as u64, Ordering::Relaxed);
+        });
+        self.certificats_maitredescles.lock().expect("lock certificats").maj(resultat, Utc::now());
+    }
+
+    pub fn certificats_maitredescles(&self) -> (u64, EtatVerification) {
+        let etat = self.certificats_maitredescles.lock().expect("lock certificats").clone();
+        (self.nombre_certificats_maitredescles.load(Ordering::Relaxed), etat)
+    }
+
+    pub fn maj_redis(&self, resultat: Result<(), String>) {
+        self.redis.lock().expect("lock redis").get_or_insert_with(EtatVerification::default).maj(resultat, Utc::now());
+    }
+
+    /// Etat de redis, None si redis n'est pas configure (ou pas encore verifie).
+    pub fn redis(&self) -> Option<EtatVerification> {
+        self.redis.lock().expect("lock redis").clone()
+    }
+
+    pub fn maj_transactions_en_attente(&self, nombre: u64) {
+        *self.transactions_en_attente.lock().expect("lock transactions") = Some((nombre, Utc::now()));
+    }
+
+    /// Dernier compte des transactions en attente et sa date, None si pas encore compte.
+    pub fn transactions_en_attente(&self) -> Option<(u64, DateTime<Utc>)> {
+        *self.transactions_en_attente.lock().expect("lock transactions")
+    }
+}
+
+impl Default for StatutService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EtatMongo {
+    pub disponible: bool,
+    pub latence_ms: Option<i64>,
+    pub erreur: Option<String>,
+    /// Transactions conservees mais pas encore completees, comptees par l'entretien
+    /// (borne CONST_LIMITE_TRANSACTIONS_EN_ATTENTE).
+    pub transactions_en_attente: Option<u64>,
+    #[serde(serialize_with = "optionepochseconds::serialize")]
+    pub transactions_en_attente_date: Option<DateTime<Utc>>,
+}
+
+/// Compte les transactions en attente (index sur le champ complete), au plus CONST_LIMITE_TRANSACTIONS_EN_ATTENTE.
+pub async fn compter_transactions_en_attente<M>(middleware: &M) -> Result<u64, Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_TRANSACTIONS)?;
+    let options = CountOptions::builder().limit(CONST_LIMITE_TRANSACTIONS_EN_ATTENTE).build();
+    Ok(collection.count_documents(doc! { TRANSACTION_CHAMP_COMPLETE: false }, options).await?)
+}
+
+/// Verifie l'acces a Mongo (ping). Les transactions en attente proviennent du dernier compte de l'entretien.
+pub async fn verifier_mongo<M>(middleware: &M, statut: &StatutService) -> EtatMongo
+    where M: MongoDao
+{
+    let debut = Utc::now();
+    let ping = match middleware.get_database() {
+        Ok(database) => database.run_command(doc! { "ping": 1 }, None).await.map_err(|e| format!("{:?}", e)),
+        Err(e) => Err(format!("{:?}", e))
+    };
+    let latence_ms = (Utc::now() - debut).num_milliseconds();
+
+    let (transactions_en_attente, transactions_en_attente_date) = match statut.transactions_en_attente() {
+        Some((nombre, date)) => (Some(nombre), Some(date)),
+        None => (None, None)
+    };
+
+    match ping {
+        Ok(_) => EtatMongo {
+            disponible: true, latence_ms: Some(latence_ms), erreur: None, transactions_en_attente, transactions_en_attente_date
+        },
+        Err(e) => EtatMongo {
+            disponible: false, latence_ms: None, erreur: Some(e), transactions_en_attente, transactions_en_attente_date
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_statut {
+    use super::*;
+    use crate::test_setup::setup;
+
+    #[test]
+    fn test_verification_fraiche() {
+        setup("test_verification_fraiche");
+        let maintenant = Utc::now();
+        let mut etat = EtatVerification::default();
+        assert!(!etat.est_frais(&maintenant, Duration::minutes(15)));
+
+        etat.maj(Ok(()), maintenant - Duration::minutes(5));
+        assert!(etat.est_frais(&maintenant, Duration::minutes(15)));
+        assert!(!etat.est_frais(&maintenant, Duration::minutes(1)));
+    }
+
+    #[test]
+    fn test_verification_erreur_posterieure() {
+        setup("test_verification_erreur_posterieure");
+        let maintenant = Utc::now();
+        let mut etat = EtatVerification::default();
+        etat.maj(Ok(()), maintenant - Duration::minutes(5));
+        etat.maj(Err("erreur".to_string()), maintenant - Duration::minutes(1));
+        assert!(!etat.est_frais(&maintenant, Duration::minutes(15)));
+    }
+
+    #[test]
+    fn test_compteurs_commandes() {
+        setup("test_compteurs_commandes");
+        let statut = StatutService::new();
+        statut.commande_traitee(true);
+        statut.commande_traitee(false);
+        assert_eq!((2, 1), statut.commandes());
+    }
+
+    #[test]
+    fn test_transactions_en_attente() {
+        setup("test_transactions_en_attente");
+        let statut = StatutService::new();
+        assert!(statut.transactions_en_attente().is_none());
+        statut.maj_transactions_en_attente(3);
+        assert_eq!(Some(3), statut.transactions_en_attente().map(|(nombre, _)| nombre));
+    }
+}
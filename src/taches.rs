@@ -0,0 +1,259 @@
+//! Taches d'entretien planifiees du domaine.
+//!
+//! Chaque tache du registre [`TACHES`] a un intervalle et une minute d'execution dans l'heure. Le
+//! declencheur est le message de cedule (chaque minute) : `traiter_cedule` depose la date dans le
+//! [`DeclencheurTaches`] sans attendre. La boucle [`thread_taches_planifiees`] execute chaque tache due
+//! dans sa propre task; l'erreur d'une tache n'empeche pas les autres.
+//!
+//! Un verrou conserve dans `Documents/taches` empeche une tache de s'executer en parallele avec
+//! elle-meme (incluant sur une autre instance). Il est libere a la fin de la tache, meme en erreur ou
+//! en panique; sinon il expire apres `duree_max_minutes`. Le statut de la derniere execution y est
+//! aussi conserve, voir la requete `getStatutTaches`.
+
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::bson::{doc, Bson};
+use millegrilles_common_rust::chrono::{DateTime, Duration, Timelike, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::futures::FutureExt;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::optionepochseconds;
+use millegrilles_common_rust::mongo_dao::{opt_chrono_datetime_as_bson_datetime, MongoDao};
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::sync::Notify;
+use serde::{Deserialize, Serialize};
+
+use crate::constantes::*;
+use crate::domain_manager::DocumentsDomainManager;
+use crate::integrite::verifier_integrite_usagers;
+use crate::verification_cles::verifier_cles_groupes;
+
+/// Planification d'une tache.
+#[derive(Clone, Debug)]
+pub struct Tache {
+    pub nom: &'static str,
+    /// Delai minimal entre deux executions.
+    pub intervalle_minutes: i64,
+    /// Minute de l'heure a laquelle la tache peut demarrer.
+    pub minute: u32,
+    /// Duree apres laquelle un verrou est considere abandonne.
+    pub duree_max_minutes: i64,
+}
+
+impl Tache {
+    /// Indique si la tache doit etre executee a la date du declencheur.
+    pub fn est_due(&self, date: &DateTime<Utc>, derniere_execution: Option<&DateTime<Utc>>) -> bool {
+        if date.minute() != self.minute {
+            return false
+        }
+        match derniere_execution {
+            // Marge d'une minute pour les variations du declencheur
+            Some(derniere) => *date - *derniere >= Duration::minutes(self.intervalle_minutes - 1),
+            None => true
+        }
+    }
+}
+
+pub const TACHE_VERIFIER_CLES_GROUPES: &str = "verifierClesGroupes";
+pub const TACHE_VERIFIER_INTEGRITE: &str = "verifierIntegrite";
+
+pub const TACHES: [Tache; 2] = [
+    Tache { nom: TACHE_VERIFIER_CLES_GROUPES, intervalle_minutes: 60, minute: 23, duree_max_minutes: 30 },
+    Tache { nom: TACHE_VERIFIER_INTEGRITE, intervalle_minutes: 1440, minute: 41, duree_max_minutes: 120 },
+];
+
+/// Etat d'une tache conserve dans Mongo.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StatutTache {
+    pub nom: String,
+    pub verrou: Option<String>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub verrou_expiration: Option<DateTime<Utc>>,
+    #[serde(default,
+        serialize_with = "optionepochseconds::serialize",
+        deserialize_with = "opt_chrono_datetime_as_bson_datetime::deserialize")]
+    pub derniere_execution: Option<DateTime<Utc>>,
+    pub dernier_succes: Option<bool>,
+    pub dernier_message: Option<String>,
+    pub derniere_duree_ms: Option<i64>,
+}
+
+/// Date du dernier declencheur (cedule), en attente de traitement par la boucle des taches.
+#[derive(Debug, Default)]
+pub struct DeclencheurTaches {
+    date: Mutex<Option<DateTime<Utc>>>,
+    notify: Notify,
+}
+
+impl DeclencheurTaches {
+    /// Depose la date du declencheur. Ne bloque pas; une date non traitee est remplacee.
+    pub fn declencher(&self, date: DateTime<Utc>) {
+        if self.date.lock().expect("lock declencheur").replace(date).is_some() {
+            warn!("DeclencheurTaches.declencher Declencheur precedent non traite, remplace");
+        }
+        self.notify.notify_one();
+    }
+
+    async fn attendre(&self) -> DateTime<Utc> {
+        loop {
+            if let Some(date) = self.date.lock().expect("lock declencheur").take() {
+                return date
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Boucle des taches planifiees. Chaque tache due est executee dans sa propre task.
+pub async fn thread_taches_planifiees<M>(gestionnaire: &'static DocumentsDomainManager, middleware: &'static M)
+    where M: GenerateurMessages + MongoDao + 'static
+{
+    loop {
+        let date = gestionnaire.taches.attendre().await;
+        if gestionnaire.arret.est_demande() {
+            info!("thread_taches_planifiees Arret demande, fin des taches planifiees");
+            break
+        }
+
+        for tache in TACHES.iter() {
+            let statut = match charger_statut(middleware, tache.nom).await {
+                Ok(inner) => inner,
+                Err(e) => {
+                    error!("thread_taches_planifiees Erreur chargement statut tache {} : {:?}", tache.nom, e);
+                    continue
+                }
+            };
+            let derniere_execution = statut.as_ref().and_then(|s| s.derniere_execution.as_ref());
+            if !tache.est_due(&date, derniere_execution) {
+                continue
+            }
+
+            let tache = tache.clone();
+            tokio::spawn(async move {
+                if let Err(e) = executer_tache(gestionnaire, middleware, &tache).await {
+                    error!("thread_taches_planifiees Erreur tache {} : {:?}", tache.nom, e);
+                }
+            });
+        }
+    }
+}
+
+/// Execute une tache sous verrou et conserve le resultat. Retourne false si la tache est deja en cours.
+pub async fn executer_tache<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, tache: &Tache)
+    -> Result<bool, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    if !acquerir_verrou(gestionnaire, middleware, tache).await? {
+        debug!("executer_tache Tache {} deja en cours, skip", tache.nom);
+        return Ok(false)
+    }
+
+    info!("executer_tache Debut tache {}", tache.nom);
+    let debut = Utc::now();
+    let execution = async {
+        match tache.nom {
+            TACHE_VERIFIER_CLES_GROUPES => verifier_cles_groupes(middleware).await,
+            TACHE_VERIFIER_INTEGRITE => verifier_integrite_usagers(gestionnaire, middleware).await,
+            _ => Err(Error::String(format!("executer_tache Tache inconnue : {}", tache.nom)))
+        }
+    };
+    // Une panique est traitee comme une erreur pour liberer le verrou
+    let resultat = match AssertUnwindSafe(execution).catch_unwind().await {
+        Ok(inner) => inner,
+        Err(_) => Err(Error::String(format!("executer_tache Panique tache {}", tache.nom)))
+    };
+    let duree_ms = (Utc::now() - debut).num_milliseconds();
+
+    let (succes, message) = match resultat {
+        Ok(()) => (true, None),
+        Err(e) => {
+            error!("executer_tache Erreur tache {} : {:?}", tache.nom, e);
+            (false, Some(format!("{:?}", e)))
+        }
+    };
+    info!("executer_tache Fin tache {} (succes {}, {} ms)", tache.nom, succes, duree_ms);
+
+    let ops = doc! {
+        "$set": {
+            "verrou": Bson::Null,
+            "verrou_expiration": Bson::Null,
+            "derniere_execution": debut,
+            "dernier_succes": succes,
+            "dernier_message": message,
+            "derniere_duree_ms": duree_ms,
+        }
+    };
+    // Liberer seulement notre verrou (il a pu expirer et etre repris par une autre instance)
+    let filtre = doc! { "nom": tache.nom, "verrou": &gestionnaire.instance_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_TACHES)?;
+    collection.update_one(filtre, ops, None).await?;
+
+    Ok(true)
+}
+
+async fn acquerir_verrou<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, tache: &Tache) -> Result<bool, Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(NOM_COLLECTION_TACHES)?;
+
+    // S'assurer que l'entree de la tache existe
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(doc! { "nom": tache.nom }, doc! { "$setOnInsert": { "nom": tache.nom } }, options).await?;
+
+    let maintenant = Utc::now();
+    let filtre = doc! {
+        "nom": tache.nom,
+        "$or": [
+            {"verrou": null},
+            {"verrou_expiration": {"$lt": maintenant}},
+        ]
+    };
+    let ops = doc! {
+        "$set": {
+            "verrou": &gestionnaire.instance_id,
+            "verrou_expiration": maintenant + Duration::minutes(tache.duree_max_minutes),
+        }
+    };
+    let resultat = collection.update_one(filtre, ops, None).await?;
+    Ok(resultat.modified_count == 1)
+}
+
+pub async fn charger_statut<M>(middleware: &M, nom: &str) -> Result<Option<StatutTache>, Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection_typed::<StatutTache>(NOM_COLLECTION_TACHES)?;
+    Ok(collection.find_one(doc! { "nom": nom }, None).await?)
+}
+
+#[cfg(test)]
+mod test_taches {
+    use super::*;
+    use millegrilles_common_rust::chrono::TimeZone;
+    use crate::test_setup::setup;
+
+    const TACHE: Tache = Tache { nom: "test", intervalle_minutes: 120, minute: 10, duree_max_minutes: 5 };
+
+    #[test]
+    fn test_tache_minute() {
+        setup("test_tache_minute");
+        let date = Utc.with_ymd_and_hms(2024, 5, 1, 3, 10, 0).unwrap();
+        assert!(TACHE.est_due(&date, None));
+        let date = Utc.with_ymd_and_hms(2024, 5, 1, 3, 11, 0).unwrap();
+        assert!(!TACHE.est_due(&date, None));
+    }
+
+    #[test]
+    fn test_tache_intervalle() {
+        setup("test_tache_intervalle");
+        let derniere = Utc.with_ymd_and_hms(2024, 5, 1, 3, 10, 2).unwrap();
+        let date = Utc.with_ymd_and_hms(2024, 5, 1, 4, 10, 0).unwrap();
+        assert!(!TACHE.est_due(&date, Some(&derniere)));
+        let date = Utc.with_ymd_and_hms(2024, 5, 1, 5, 10, 0).unwrap();
+        assert!(TACHE.est_due(&date, Some(&derniere)));
+    }
+}
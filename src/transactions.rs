@@ -1,17 +1,18 @@
 use log::{debug, error};
 
-use millegrilles_common_rust::bson::{Bson, doc};
+use millegrilles_common_rust::bson::{Bson, doc, Document};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::chrono::Utc;
 use millegrilles_common_rust::common_messages::verifier_reponse_ok;
 use millegrilles_common_rust::constantes::*;
 use millegrilles_common_rust::db_structs::TransactionValide;
+use millegrilles_common_rust::domaines_traits::GestionnaireDomaineV2;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::{get_domaine_action, serde_json};
 use millegrilles_common_rust::middleware::{sauvegarder_traiter_transaction, sauvegarder_traiter_transaction_v2};
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
 use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, convertir_to_bson_array, MongoDao};
-use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions};
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::recepteur_messages::MessageValide;
 use millegrilles_common_rust::serde_json::json;
@@ -22,6 +23,11 @@ use serde::Serialize;
 use crate::common::*;
 use crate::constantes::*;
 use crate::domain_manager::DocumentsDomainManager;
+use crate::effacement::COLLECTIONS_USAGER_NON_VOLATILES;
+use crate::evenements_maj::emettre_maj_transaction;
+use crate::integrite::reparer_usager;
+use crate::journal::{enregistrer_activite, preparer_activite};
+use crate::quotas::invalider_utilisation;
 
 pub async fn aiguillage_transaction<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -34,7 +40,11 @@ pub async fn aiguillage_transaction<M>(gestionnaire: &DocumentsDomainManager, mi
         },
         None => Err(format!("transactions.aiguillage_transaction: Transaction {} n'a pas de routage - skip", transaction.transaction.id))?,
     };
-    match action.as_str() {
+
+    // Preparer l'entree du journal d'activite avant de consommer la transaction
+    let activite = preparer_activite(&transaction, action.as_str())?;
+
+    let resultat = match action.as_str() {
         TRANSACTION_SAUVEGARDER_CATEGORIE_USAGER => transaction_sauvegarder_categorie_usager(gestionnaire, middleware, transaction, session).await,
         TRANSACTION_SAUVEGARDER_GROUPE_USAGER => transaction_sauvegarder_groupe_usager(gestionnaire, middleware, transaction, session).await,
         TRANSACTION_SAUVEGARDER_DOCUMENT => transaction_sauvegarder_document(gestionnaire, middleware, transaction, session).await,
@@ -42,8 +52,19 @@ pub async fn aiguillage_transaction<M>(gestionnaire: &DocumentsDomainManager, mi
         TRANSACTION_RECUPERER_DOCUMENT => transaction_recuperer_document(gestionnaire, middleware, transaction, session).await,
         TRANSACTION_SUPPRIMER_GROUPE => transaction_supprimer_groupe(gestionnaire, middleware, transaction, session).await,
         TRANSACTION_RECUPERER_GROUPE => transaction_recuperer_groupe(gestionnaire, middleware, transaction, session).await,
+        TRANSACTION_COPIER_DOCUMENT => transaction_copier_document(gestionnaire, middleware, transaction, session).await,
+        TRANSACTION_COPIER_GROUPE => transaction_copier_groupe(gestionnaire, middleware, transaction, session).await,
+        TRANSACTION_SUPPRIMER_USAGER => transaction_supprimer_usager(gestionnaire, middleware, transaction, session).await,
+        TRANSACTION_MIGRER_CHIFFRAGE => transaction_migrer_chiffrage(gestionnaire, middleware, transaction, session).await,
+        TRANSACTION_REPARER_INTEGRITE => transaction_reparer_integrite(gestionnaire, middleware, transaction, session).await,
         _ => Err(Error::String(format!("core_backup.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.transaction.id, action))),
+    }?;
+
+    if let Some(activite) = activite {
+        enregistrer_activite(gestionnaire, middleware, activite, session).await?;
     }
+
+    Ok(resultat)
 }
 
 // pub async fn consommer_transaction<M>(middleware: &M, m: MessageValide, gestionnaire: &DocumentsDomainManager)
@@ -137,7 +158,7 @@ async fn transaction_sauvegarder_categorie_usager<M>(gestionnaire: &DocumentsDom
             "$currentDate": {CHAMP_MODIFICATION: true},
         };
 
-        let collection = middleware.get_collection(NOM_COLLECTION_CATEGORIES_USAGERS)?;
+        let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_CATEGORIES_USAGERS).as_str())?;
         let options = FindOneAndUpdateOptions::builder()
             .upsert(true)
             .return_document(ReturnDocument::After)
@@ -170,7 +191,7 @@ async fn transaction_sauvegarder_categorie_usager<M>(gestionnaire: &DocumentsDom
             "$currentDate": {CHAMP_MODIFICATION: true},
         };
 
-        let collection = middleware.get_collection(NOM_COLLECTION_CATEGORIES_USAGERS_VERSION)?;
+        let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_CATEGORIES_USAGERS_VERSION).as_str())?;
         let options = UpdateOptions::builder().upsert(true).build();
         let resultat = match collection.update_one_with_session(filtre, ops, options, session).await {
             Ok(inner) => inner,
@@ -188,11 +209,10 @@ async fn transaction_sauvegarder_categorie_usager<M>(gestionnaire: &DocumentsDom
         }
     }
 
-    // Emettre evenement maj
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_SAUVEGARDER_CATEGORIE_USAGER, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &document_categorie).await?;
+    // Emettre evenement maj (contrat historique), pas lors d'une reconstruction
+    if gestionnaire.reconstruction.is_none() {
+        emettre_maj_transaction(middleware, session, &transaction.transaction.id, TRANSACTION_SAUVEGARDER_CATEGORIE_USAGER, &user_id, &document_categorie).await?;
+    }
 
     // Ok(Some(middleware.reponse_ok(None, None)?))
     let reponse = ReponseTransactionSauvegarderCategorie { ok: true, category_id: categorie_id };
@@ -232,6 +252,7 @@ async fn transaction_sauvegarder_groupe_usager<M>(gestionnaire: &DocumentsDomain
         "categorie_id": &transaction_groupe.categorie_id,
         "user_id": &user_id,
         CHAMP_CREATION: Utc::now(),
+        "statistiques": {"nombre_documents": 0i64, "nombre_documents_supprimes": 0i64, "taille_chiffre": 0i64},
     };
 
     let format_str: &str = transaction_groupe.format.into();
@@ -257,7 +278,7 @@ async fn transaction_sauvegarder_groupe_usager<M>(gestionnaire: &DocumentsDomain
             "$currentDate": {CHAMP_MODIFICATION: true},
         };
 
-        let collection = middleware.get_collection(NOM_COLLECTION_GROUPES_USAGERS)?;
+        let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
         let options = FindOneAndUpdateOptions::builder()
             .upsert(true)
             .return_document(ReturnDocument::After)
@@ -276,11 +297,10 @@ async fn transaction_sauvegarder_groupe_usager<M>(gestionnaire: &DocumentsDomain
         resultat
     };
 
-    // Emettre evenement maj
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_SAUVEGARDER_GROUPE_USAGER, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &document_groupe).await?;
+    // Emettre evenement maj (contrat historique), pas lors d'une reconstruction
+    if gestionnaire.reconstruction.is_none() {
+        emettre_maj_transaction(middleware, session, &transaction.transaction.id, TRANSACTION_SAUVEGARDER_GROUPE_USAGER, &user_id, &document_groupe).await?;
+    }
 
     let reponse = ReponseTransactionSauvegarderGroupe { ok: true, group_id: groupe_id };
     Ok(Some(middleware.build_reponse(reponse)?.0))
@@ -345,7 +365,7 @@ async fn transaction_sauvegarder_document<M>(gestionnaire: &DocumentsDomainManag
             "$currentDate": {CHAMP_MODIFICATION: true},
         };
 
-        let collection = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
+        let collection = middleware.get_collection_typed::<DocDocument>(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
         let options = FindOneAndUpdateOptions::builder()
             .upsert(true)
             .return_document(ReturnDocument::After)
@@ -361,17 +381,18 @@ async fn transaction_sauvegarder_document<M>(gestionnaire: &DocumentsDomainManag
         resultat
     };
 
-    // Emettre evenement maj
-    let routage = RoutageMessageAction::builder(DOMAINE_NOM, TRANSACTION_SAUVEGARDER_DOCUMENT, vec![Securite::L2Prive])
-        .partition(user_id)
-        .build();
-    middleware.emettre_evenement(routage, &document_doc).await?;
+    maj_statistiques_groupe(gestionnaire, middleware, &user_id, &document_doc.groupe_id, session).await?;
+
+    // Emettre evenement maj (contrat historique), pas lors d'une reconstruction
+    if gestionnaire.reconstruction.is_none() {
+        emettre_maj_transaction(middleware, session, &transaction.transaction.id, TRANSACTION_SAUVEGARDER_DOCUMENT, &user_id, &document_doc).await?;
+    }
 
     let reponse = ReponseTransactionSauvegarderDocument { ok: true, doc_id };
     Ok(Some(middleware.build_reponse(reponse)?.0))
 }
 
-async fn transaction_supprimer_document<M>(_gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+async fn transaction_supprimer_document<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
 {
@@ -399,20 +420,22 @@ async fn transaction_supprimer_document<M>(_gestionnaire: &DocumentsDomainManage
         "$currentDate": {CHAMP_MODIFICATION: true, NOM_CHAMP_SUPPRIME_DATE: true},
     };
 
-    let collection = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
-    match collection.find_one_and_update_with_session(filtre, ops, None, session).await {
+    let collection = middleware.get_collection_typed::<DocDocument>(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+    let groupe_id = match collection.find_one_and_update_with_session(filtre, ops, None, session).await {
         Ok(inner) => match inner {
-            Some(_inner) => (),
+            Some(inner) => inner.groupe_id,
             None => Err(format!("transactions.transaction_supprimer_document Erreur insert/maj groupe usager (None)"))?
         },
         Err(e) => Err(format!("transactions.transaction_supprimer_document Erreur insert/maj groupe usager (exec) : {:?}", e))?
     };
 
+    maj_statistiques_groupe(gestionnaire, middleware, &user_id, &groupe_id, session).await?;
+
     let reponse = ReponseTransactionSauvegarderDocument { ok: true, doc_id };
     Ok(Some(middleware.build_reponse(reponse)?.0))
 }
 
-async fn transaction_recuperer_document<M>(_gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+async fn transaction_recuperer_document<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
 {
@@ -441,20 +464,22 @@ async fn transaction_recuperer_document<M>(_gestionnaire: &DocumentsDomainManage
         "$currentDate": {CHAMP_MODIFICATION: true},
     };
 
-    let collection = middleware.get_collection_typed::<DocDocument>(NOM_COLLECTION_DOCUMENTS_USAGERS)?;
-    match collection.find_one_and_update_with_session(filtre, ops, None, session).await {
+    let collection = middleware.get_collection_typed::<DocDocument>(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+    let groupe_id = match collection.find_one_and_update_with_session(filtre, ops, None, session).await {
         Ok(inner) => match inner {
-            Some(_inner) => (),
+            Some(inner) => inner.groupe_id,
             None => Err(format!("transactions.transaction_recuperer_document Erreur insert/maj groupe usager (None)"))?
         },
         Err(e) => Err(format!("transactions.transaction_recuperer_document Erreur insert/maj groupe usager (exec) : {:?}", e))?
     };
 
+    maj_statistiques_groupe(gestionnaire, middleware, &user_id, &groupe_id, session).await?;
+
     let reponse = ReponseTransactionSauvegarderDocument { ok: true, doc_id };
     Ok(Some(middleware.build_reponse(reponse)?.0))
 }
 
-async fn transaction_supprimer_groupe<M>(_gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+async fn transaction_supprimer_groupe<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
 {
@@ -482,7 +507,7 @@ async fn transaction_supprimer_groupe<M>(_gestionnaire: &DocumentsDomainManager,
         "$currentDate": {CHAMP_MODIFICATION: true, NOM_CHAMP_SUPPRIME_DATE: true},
     };
 
-    let collection = middleware.get_collection_typed::<DocGroupeUsager>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let collection = middleware.get_collection_typed::<DocGroupeUsager>(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
     match collection.find_one_and_update_with_session(filtre, ops, None, session).await {
         Ok(inner) => match inner {
             Some(_inner) => (),
@@ -495,7 +520,7 @@ async fn transaction_supprimer_groupe<M>(_gestionnaire: &DocumentsDomainManager,
     Ok(Some(middleware.build_reponse(reponse)?.0))
 }
 
-async fn transaction_recuperer_groupe<M>(_gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+async fn transaction_recuperer_groupe<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
 {
@@ -524,7 +549,7 @@ async fn transaction_recuperer_groupe<M>(_gestionnaire: &DocumentsDomainManager,
         "$currentDate": {CHAMP_MODIFICATION: true},
     };
 
-    let collection = middleware.get_collection_typed::<DocGroupeUsager>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let collection = middleware.get_collection_typed::<DocGroupeUsager>(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
     match collection.find_one_and_update_with_session(filtre, ops, None, session).await {
         Ok(inner) => match inner {
             Some(_inner) => (),
@@ -536,3 +561,365 @@ async fn transaction_recuperer_groupe<M>(_gestionnaire: &DocumentsDomainManager,
     let reponse = ReponseTransactionSauvegarderGroupe { ok: true, group_id: groupe_id };
     Ok(Some(middleware.build_reponse(reponse)?.0))
 }
+
+/// Prepare la copie d'un document. Le contenu rechiffre remplace le contenu source s'il est fourni.
+/// Identifiant du document derive a l'index donne d'une transaction qui cree plusieurs documents
+/// (copie de groupe). L'index suit l'ordre des doc_id source, stable lors d'une regeneration.
+pub fn doc_id_derive(transaction_id: &str, index: usize) -> String {
+    format!("{}{}{}", transaction_id, CONST_SEPARATEUR_DOC_ID_DERIVE, index)
+}
+
+fn preparer_copie_document(source: DocDocument, doc_id: String, groupe_id: String, contenu: Option<ContenuChiffreDocument>) -> DocDocument {
+    let mut copie = DocDocument {
+        doc_id,
+        groupe_id,
+        categorie_version: source.categorie_version,
+        data_chiffre: source.data_chiffre,
+        supprime: None,
+        supprime_date: None,
+        cle_id: source.cle_id,
+        format: source.format,
+        nonce: source.nonce,
+        compression: source.compression,
+        header: source.header,
+    };
+
+    if let Some(contenu) = contenu {
+        copie.data_chiffre = contenu.data_chiffre;
+        copie.cle_id = contenu.cle_id;
+        copie.format = contenu.format;
+        copie.nonce = contenu.nonce;
+        copie.compression = contenu.compression;
+        copie.header = None;
+    }
+
+    copie
+}
+
+async fn inserer_document_copie<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, user_id: &str, document: DocDocument, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        "doc_id": &document.doc_id,
+        "user_id": user_id,
+    };
+
+    let set_on_insert = doc! {
+        "doc_id": &document.doc_id,
+        "groupe_id": &document.groupe_id,
+        "user_id": user_id,
+        CHAMP_CREATION: Utc::now(),
+    };
+
+    let format_str: &str = document.format.into();
+    let set_ops = doc! {
+        "categorie_version": document.categorie_version,
+        "data_chiffre": document.data_chiffre,
+        "format": format_str,
+        "header": document.header,
+        "cle_id": document.cle_id,
+        "nonce": document.nonce,
+        "compression": document.compression,
+    };
+
+    let ops = doc! {
+        "$set": &set_ops,
+        "$setOnInsert": &set_on_insert,
+        "$currentDate": {CHAMP_MODIFICATION: true},
+    };
+
+    let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    if let Err(e) = collection.update_one_with_session(filtre, ops, options, session).await {
+        Err(format!("transactions.inserer_document_copie Erreur insert copie document (exec) : {:?}", e))?
+    }
+
+    Ok(())
+}
+
+async fn transaction_copier_document<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_copier_document Consommer transaction : {:?}", &transaction.transaction.id);
+    let uuid_transaction = transaction.transaction.id.clone();
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(inner) => inner.to_owned(),
+        None => Err(format!("transactions.transaction_copier_document User_id absent du certificat (cert)"))?
+    };
+
+    let transaction_copie: TransactionCopierDocument = match serde_json::from_str(transaction.transaction.contenu.as_str()) {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_copier_document Erreur conversion transaction : {:?}", e))?
+    };
+
+    let collection = middleware.get_collection_typed::<DocDocument>(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+    let filtre = doc! { "doc_id": &transaction_copie.doc_id, "user_id": &user_id };
+    let doc_source = match collection.find_one_with_session(filtre, None, session).await? {
+        Some(inner) => inner,
+        None => Err(format!("transactions.transaction_copier_document Document source {} inconnu", transaction_copie.doc_id))?
+    };
+
+    // Le nouveau doc_id est l'id de la transaction
+    let doc_id = uuid_transaction;
+    let copie = preparer_copie_document(doc_source, doc_id.clone(), transaction_copie.groupe_id.clone(), transaction_copie.contenu);
+    inserer_document_copie(gestionnaire, middleware, &user_id, copie, session).await?;
+    maj_statistiques_groupe(gestionnaire, middleware, &user_id, &transaction_copie.groupe_id, session).await?;
+
+    let reponse = ReponseTransactionSauvegarderDocument { ok: true, doc_id };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct DocumentCopie {
+    doc_id_source: String,
+    doc_id: String,
+}
+
+#[derive(Serialize)]
+struct ReponseTransactionCopierGroupe {
+    ok: bool,
+    group_id: String,
+    documents: Vec<DocumentCopie>,
+}
+
+async fn transaction_copier_groupe<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_copier_groupe Consommer transaction : {:?}", &transaction.transaction.id);
+    let uuid_transaction = transaction.transaction.id.clone();
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(inner) => inner.to_owned(),
+        None => Err(format!("transactions.transaction_copier_groupe User_id absent du certificat (cert)"))?
+    };
+
+    let transaction_copie: TransactionCopierGroupe = match serde_json::from_str(transaction.transaction.contenu.as_str()) {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_copier_groupe Erreur conversion transaction : {:?}", e))?
+    };
+
+    let collection_groupes = middleware.get_collection_typed::<DocGroupeUsager>(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
+    let filtre = doc! { "groupe_id": &transaction_copie.groupe_id, "user_id": &user_id };
+    let groupe_source = match collection_groupes.find_one_with_session(filtre, None, session).await? {
+        Some(inner) => inner,
+        None => Err(format!("transactions.transaction_copier_groupe Groupe source {} inconnu", transaction_copie.groupe_id))?
+    };
+
+    // Le nouveau groupe_id est l'id de la transaction. La cle du groupe source est conservee.
+    let groupe_id = uuid_transaction.clone();
+    let (data_chiffre, format, nonce, header) = match transaction_copie.contenu {
+        Some(contenu) => (contenu.data_chiffre, contenu.format, contenu.nonce, None),
+        None => (groupe_source.data_chiffre, groupe_source.format, groupe_source.nonce, groupe_source.header)
+    };
+
+    {
+        let filtre = doc! {
+            "groupe_id": &groupe_id,
+            "user_id": &user_id,
+        };
+
+        let set_on_insert = doc! {
+            "groupe_id": &groupe_id,
+            "categorie_id": &groupe_source.categorie_id,
+            "user_id": &user_id,
+            CHAMP_CREATION: Utc::now(),
+        };
+
+        let format_str: &str = format.into();
+        let set_ops = doc! {
+            "data_chiffre": data_chiffre,
+            "format": format_str,
+            "header": header,
+            "ref_hachage_bytes": &groupe_source.ref_hachage_bytes,
+            "cle_id": &groupe_source.cle_id,
+            "nonce": nonce,
+        };
+
+        let ops = doc! {
+            "$set": &set_ops,
+            "$setOnInsert": &set_on_insert,
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        };
+
+        let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
+        let options = UpdateOptions::builder().upsert(true).build();
+        if let Err(e) = collection.update_one_with_session(filtre, ops, options, session).await {
+            Err(format!("transactions.transaction_copier_groupe Erreur insert copie groupe (exec) : {:?}", e))?
+        }
+    }
+
+    // Charger les documents actifs du groupe source. Le tri par doc_id garantit des
+    // identifiants de copie stables lors d'une regeneration.
+    let documents_source = {
+        let filtre = doc! {
+            "groupe_id": &transaction_copie.groupe_id,
+            "user_id": &user_id,
+            "supprime": {"$ne": true},
+        };
+        let options = FindOptions::builder().sort(doc! {"doc_id": 1}).build();
+        let collection = middleware.get_collection_typed::<DocDocument>(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+        let mut curseur = collection.find_with_session(filtre, options, session).await?;
+        let mut documents = Vec::new();
+        while let Some(row) = curseur.next(session).await {
+            documents.push(row?);
+        }
+        documents
+    };
+
+    let mut documents_copies = Vec::with_capacity(documents_source.len());
+    for (idx, doc_source) in documents_source.into_iter().enumerate() {
+        let doc_id_source = doc_source.doc_id.clone();
+        let doc_id = doc_id_derive(uuid_transaction.as_str(), idx);
+        let copie = preparer_copie_document(doc_source, doc_id.clone(), groupe_id.clone(), None);
+        inserer_document_copie(gestionnaire, middleware, &user_id, copie, session).await?;
+        documents_copies.push(DocumentCopie { doc_id_source, doc_id });
+    }
+    maj_statistiques_groupe(gestionnaire, middleware, &user_id, &groupe_id, session).await?;
+
+    let reponse = ReponseTransactionCopierGroupe { ok: true, group_id: groupe_id, documents: documents_copies };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+/// Recalcule les statistiques des documents d'un groupe et les conserve sur le groupe.
+pub async fn maj_statistiques_groupe<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, user_id: &str, groupe_id: &str, session: &mut ClientSession)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let pipeline = vec![
+        doc! { "$match": { "user_id": user_id, "groupe_id": groupe_id } },
+        doc! { "$group": {
+            "_id": Bson::Null,
+            "nombre_documents": {"$sum": {"$cond": [{"$eq": ["$supprime", true]}, 0, 1]}},
+            "nombre_documents_supprimes": {"$sum": {"$cond": [{"$eq": ["$supprime", true]}, 1, 0]}},
+            "taille_chiffre": {"$sum": {"$strLenBytes": {"$ifNull": ["$data_chiffre", ""]}}},
+            "derniere_modification_document": {"$max": format!("${}", CHAMP_MODIFICATION)},
+        }},
+    ];
+
+    let collection_documents = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+    let statistiques: StatistiquesGroupe = {
+        let mut curseur = collection_documents.aggregate_with_session(pipeline, None, session).await?;
+        match curseur.next(session).await {
+            Some(row) => convertir_bson_deserializable(row?)?,
+            None => StatistiquesGroupe::default()
+        }
+    };
+
+    let doc_statistiques: Document = doc! {
+        "nombre_documents": statistiques.nombre_documents,
+        "nombre_documents_supprimes": statistiques.nombre_documents_supprimes,
+        "taille_chiffre": statistiques.taille_chiffre,
+        "derniere_modification_document": statistiques.derniere_modification_document,
+    };
+
+    let filtre = doc! { "user_id": user_id, "groupe_id": groupe_id };
+    let ops = doc! { "$set": { "statistiques": doc_statistiques } };
+    let collection_groupes = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
+    collection_groupes.update_one_with_session(filtre, ops, None, session).await?;
+
+    Ok(())
+}
+
+/// Remplace le contenu chiffre d'un groupe ou d'un document et retire les champs de l'ancien format.
+async fn transaction_migrer_chiffrage<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_migrer_chiffrage Consommer transaction : {:?}", &transaction.transaction.id);
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(inner) => inner.to_owned(),
+        None => Err(format!("transactions.transaction_migrer_chiffrage User_id absent du certificat (cert)"))?
+    };
+
+    let migration: TransactionMigrerChiffrage = match serde_json::from_str(transaction.transaction.contenu.as_str()) {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_migrer_chiffrage Erreur conversion transaction : {:?}", e))?
+    };
+
+    let format_str: &str = migration.format.into();
+    let mut set_ops = doc! {
+        "data_chiffre": &migration.data_chiffre,
+        "cle_id": &migration.cle_id,
+        "format": format_str,
+        "nonce": &migration.nonce,
+        "migration_chiffrage": {
+            "transaction_id": &transaction.transaction.id,
+            "date": transaction.transaction.estampille,
+        },
+    };
+
+    let resultat = match migration.doc_id.as_ref() {
+        Some(doc_id) => {
+            set_ops.insert("compression", migration.compression.clone());
+            let filtre = doc! { "doc_id": doc_id, "groupe_id": &migration.groupe_id, "user_id": &user_id };
+            let ops = doc! {
+                "$set": set_ops,
+                "$unset": {"header": true},
+                "$currentDate": {CHAMP_MODIFICATION: true},
+            };
+            let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_DOCUMENTS_USAGERS).as_str())?;
+            let resultat = collection.update_one_with_session(filtre, ops, None, session).await?;
+            maj_statistiques_groupe(gestionnaire, middleware, &user_id, &migration.groupe_id, session).await?;
+            // La taille du contenu change hors de la reservation de quota
+            invalider_utilisation(middleware, &user_id, session).await?;
+            resultat
+        },
+        None => {
+            let filtre = doc! { "groupe_id": &migration.groupe_id, "user_id": &user_id };
+            let ops = doc! {
+                "$set": set_ops,
+                "$unset": {"header": true, "ref_hachage_bytes": true},
+                "$currentDate": {CHAMP_MODIFICATION: true},
+            };
+            let collection = middleware.get_collection(gestionnaire.nom_collection(NOM_COLLECTION_GROUPES_USAGERS).as_str())?;
+            collection.update_one_with_session(filtre, ops, None, session).await?
+        }
+    };
+
+    if resultat.matched_count == 0 {
+        Err(format!("transactions.transaction_migrer_chiffrage Objet inconnu (groupe {}, doc {:?})", migration.groupe_id, migration.doc_id))?
+    }
+
+    let reponse = json!({"ok": true});
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+/// Purge les donnees d'un usager dans toutes les collections volatiles du domaine.
+async fn transaction_supprimer_usager<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_supprimer_usager Consommer transaction : {:?}", &transaction.transaction.id);
+    let transaction_usager: TransactionSupprimerUsager = match serde_json::from_str(transaction.transaction.contenu.as_str()) {
+        Ok(t) => t,
+        Err(e) => Err(format!("transactions.transaction_supprimer_usager Erreur conversion transaction : {:?}", e))?
+    };
+
+    let filtre = doc! { "user_id": &transaction_usager.user_id };
+    for nom_collection in gestionnaire.get_collections_volatiles()? {
+        let collection = middleware.get_collection(gestionnaire.nom_collection(nom_collection.as_str()).as_str())?;
+        let resultat = collection.delete_many_with_session(filtre.clone(), None, session).await?;
+        debug!("transaction_supprimer_usager {} documents supprimes de {}", resultat.deleted_count, nom_collection);
+    }
+    // Quotas (remplacement et compteur), rapport d'integrite et evenements non publies de l'usager
+    for nom_collection in COLLECTIONS_USAGER_NON_VOLATILES {
+        let collection = middleware.get_collection(nom_collection)?;
+        let resultat = collection.delete_many_with_session(filtre.clone(), None, session).await?;
+        debug!("transaction_supprimer_usager {} documents supprimes de {}", resultat.deleted_count, nom_collection);
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Repare les references invalides des donnees d'un usager, voir le module integrite.
+async fn transaction_reparer_integrite<M>(gestionnaire: &DocumentsDomainManager, middleware: &M, transaction: TransactionValide, session: &mut ClientSession)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("transaction_reparer_integrite Consommer transaction : {:?}", &transaction.transaction.id);
+    let rapport = reparer_usager(gestionnaire, middleware, &transaction, session).await?;
+    let reponse = json!({"ok": true, "rapport": rapport});
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
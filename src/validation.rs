@@ -0,0 +1,230 @@
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::millegrilles_cryptographie::chiffrage::FormatChiffrage;
+use millegrilles_common_rust::millegrilles_cryptographie::maitredescles::SignatureDomaines;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::ClientSession;
+use millegrilles_common_rust::serde_json;
+use serde::Deserialize;
+
+use crate::configuration::ConfigurationDocuments;
+use crate::constantes::*;
+
+/// Erreur de validation du contenu chiffre d'un document.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErreurContenu {
+    TailleExcedee,
+    FormatInvalide,
+    CompressionInvalide,
+    HeaderObsolete,
+    DocIdReserve,
+}
+
+impl ErreurContenu {
+    pub fn code(&self) -> usize {
+        match self {
+            ErreurContenu::TailleExcedee => ERREUR_TAILLE_DOCUMENT,
+            ErreurContenu::FormatInvalide => ERREUR_FORMAT_DATA_CHIFFRE,
+            ErreurContenu::CompressionInvalide => ERREUR_COMPRESSION_INVALIDE,
+            ErreurContenu::HeaderObsolete => ERREUR_HEADER_OBSOLETE,
+            ErreurContenu::DocIdReserve => ERREUR_DOC_ID_RESERVE,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErreurContenu::TailleExcedee => "Document content exceeds maximum size",
+            ErreurContenu::FormatInvalide => "Invalid data_chiffre encoding for format",
+            ErreurContenu::CompressionInvalide => "Unsupported compression",
+            ErreurContenu::HeaderObsolete => "Obsolete header encryption scheme not accepted for new documents",
+            ErreurContenu::DocIdReserve => "doc_id format is reserved for derived documents",
+        }
+    }
+}
+
+/// Valide le contenu chiffre d'un document (taille, encodage et compression).
+pub fn valider_contenu_document(configuration: &ConfigurationDocuments, data_chiffre: &str, format: FormatChiffrage, compression: Option<&String>)
+    -> Result<(), ErreurContenu>
+{
+    if data_chiffre.len() > configuration.taille_max_document {
+        Err(ErreurContenu::TailleExcedee)?
+    }
+
+    let format_str: &str = format.into();
+    let data_base64 = match format_str {
+        // Formats courants : base64 sans prefixe
+        f if CONST_FORMATS_CHIFFRAGE_SUPPORTES.contains(&f) => data_chiffre,
+        // Anciens formats : multibase base64 (prefixe 'm')
+        _ => match data_chiffre.strip_prefix('m') {
+            Some(inner) => inner,
+            None => Err(ErreurContenu::FormatInvalide)?
+        }
+    };
+    if !est_base64(data_base64) {
+        Err(ErreurContenu::FormatInvalide)?
+    }
+
+    if let Some(compression) = compression {
+        if !CONST_COMPRESSIONS_SUPPORTEES.contains(&compression.as_str()) {
+            Err(ErreurContenu::CompressionInvalide)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Un nouveau document doit utiliser le nonce. Le header seul (ancien format) est refuse.
+pub fn valider_nouveau_document(header: Option<&String>, nonce: Option<&String>) -> Result<(), ErreurContenu> {
+    if header.is_some() && nonce.is_none() {
+        Err(ErreurContenu::HeaderObsolete)?
+    }
+    Ok(())
+}
+
+/// Un doc_id fourni par le client pour un nouveau document ne doit pas utiliser le format des
+/// doc_id derives (copie de groupe), sinon il pourrait entrer en conflit avec une copie.
+pub fn valider_doc_id_client(doc_id: &str) -> Result<(), ErreurContenu> {
+    if doc_id.contains(CONST_SEPARATEUR_DOC_ID_DERIVE) {
+        Err(ErreurContenu::DocIdReserve)?
+    }
+    Ok(())
+}
+
+/// Erreur de validation d'une cle attachee a une commande.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErreurCle {
+    FormatInvalide,
+    DomaineInvalide,
+    CleIdInvalide,
+}
+
+impl ErreurCle {
+    pub fn code(&self) -> usize {
+        match self {
+            ErreurCle::FormatInvalide => ERREUR_CLE_FORMAT,
+            ErreurCle::DomaineInvalide => ERREUR_CLE_DOMAINE,
+            ErreurCle::CleIdInvalide => ERREUR_CLE_ID,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErreurCle::FormatInvalide => "Invalid attached key",
+            ErreurCle::DomaineInvalide => "Attached key is not for the Documents domain",
+            ErreurCle::CleIdInvalide => "Attached key does not match cle_id",
+        }
+    }
+}
+
+/// Contenu de la commande d'ajout de cle (MaitreDesCles) attachee.
+#[derive(Deserialize)]
+struct CleAttachee {
+    signature: SignatureDomaines,
+}
+
+/// Verifie que la cle attachee (contenu du message `cle`) est pour le domaine Documents et que son
+/// identifiant correspond au cle_id de l'objet. Sinon la cle ne pourrait pas etre recuperee.
+pub fn valider_cle_attachee(contenu: &str, cle_id: Option<&String>) -> Result<(), ErreurCle> {
+    let cle: CleAttachee = serde_json::from_str(contenu).map_err(|_| ErreurCle::FormatInvalide)?;
+
+    if !cle.signature.domaines.iter().any(|d| d.as_str() == DOMAINE_NOM) {
+        Err(ErreurCle::DomaineInvalide)?
+    }
+
+    let cle_ref = cle.signature.get_cle_ref().map_err(|_| ErreurCle::FormatInvalide)?;
+    match cle_id {
+        Some(cle_id) if cle_id.as_str() == cle_ref.as_str() => Ok(()),
+        _ => Err(ErreurCle::CleIdInvalide)
+    }
+}
+
+/// Erreur de reference vers un objet parent (categorie, version ou groupe) de l'usager.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErreurReference {
+    CategorieInconnue,
+    VersionCategorieInconnue,
+    GroupeInconnu,
+    GroupeSupprime,
+}
+
+impl ErreurReference {
+    pub fn code(&self) -> usize {
+        match self {
+            ErreurReference::CategorieInconnue => ERREUR_CATEGORIE_INCONNUE,
+            ErreurReference::VersionCategorieInconnue => ERREUR_VERSION_CATEGORIE_INCONNUE,
+            ErreurReference::GroupeInconnu => ERREUR_GROUPE_INCONNU,
+            ErreurReference::GroupeSupprime => ERREUR_GROUPE_SUPPRIME,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErreurReference::CategorieInconnue => "Unknown categorie_id",
+            ErreurReference::VersionCategorieInconnue => "Unknown categorie_version for the group category",
+            ErreurReference::GroupeInconnu => "Unknown groupe_id",
+            ErreurReference::GroupeSupprime => "Group is deleted",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GroupeParent {
+    categorie_id: String,
+    supprime: Option<bool>,
+}
+
+/// Verifie que la categorie d'un groupe existe pour l'usager.
+pub async fn verifier_reference_categorie<M>(middleware: &M, user_id: &str, categorie_id: &str, session: &mut ClientSession)
+    -> Result<Result<(), ErreurReference>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! { "user_id": user_id, "categorie_id": categorie_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_CATEGORIES_USAGERS)?;
+    match collection.find_one_with_session(filtre, None, session).await? {
+        Some(_) => Ok(Ok(())),
+        None => Ok(Err(ErreurReference::CategorieInconnue))
+    }
+}
+
+/// Verifie que le groupe d'un document existe pour l'usager, n'est pas supprime et que la version de
+/// categorie du document existe pour la categorie du groupe.
+pub async fn verifier_references_document<M>(middleware: &M, user_id: &str, groupe_id: &str, categorie_version: i32, session: &mut ClientSession)
+    -> Result<Result<(), ErreurReference>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! { "user_id": user_id, "groupe_id": groupe_id };
+    let collection = middleware.get_collection(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let groupe: GroupeParent = match collection.find_one_with_session(filtre, None, session).await? {
+        Some(inner) => convertir_bson_deserializable(inner)?,
+        None => return Ok(Err(ErreurReference::GroupeInconnu))
+    };
+    if Some(true) == groupe.supprime {
+        return Ok(Err(ErreurReference::GroupeSupprime))
+    }
+
+    let filtre = doc! { "user_id": user_id, "categorie_id": &groupe.categorie_id, "version": categorie_version };
+    let collection = middleware.get_collection(NOM_COLLECTION_CATEGORIES_USAGERS_VERSION)?;
+    match collection.find_one_with_session(filtre, None, session).await? {
+        Some(_) => Ok(Ok(())),
+        None => Ok(Err(ErreurReference::VersionCategorieInconnue))
+    }
+}
+
+/// Verifie que la valeur est du base64 standard bien forme, avec ou sans padding.
+fn est_base64(valeur: &str) -> bool {
+    if valeur.is_empty() {
+        return false
+    }
+
+    let sans_padding = valeur.trim_end_matches('=');
+    let padding = valeur.len() - sans_padding.len();
+    if padding > 2 || (padding > 0 && valeur.len() % 4 != 0) {
+        return false
+    }
+    // Une longueur de 4n+1 caracteres ne peut pas etre produite par un encodeur base64.
+    if sans_padding.len() % 4 == 1 {
+        return false
+    }
+
+    sans_padding.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'/')
+}
@@ -0,0 +1,164 @@
+//! Detection des groupes dont la cle n'est pas connue de MaitreDesCles.
+//!
+//! L'entretien verifie periodiquement les cles (cle_id ou ref_hachage_bytes) des groupes. Un groupe
+//! dont la cle n'est pas retournee par MaitreDesCles est marque `cle_inconnue`; son contenu ne peut
+//! plus etre dechiffre. Le marqueur est retire si la cle redevient disponible.
+
+use std::collections::{HashMap, HashSet};
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::chrono::{Duration, Utc};
+use millegrilles_common_rust::common_messages::RequeteDechiffrage;
+use millegrilles_common_rust::constantes::*;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::recepteur_messages::TypeMessage;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use serde::Deserialize;
+
+use crate::constantes::*;
+
+/// Nombre de cles verifiees par requete a MaitreDesCles.
+const CONST_CLES_PAR_REQUETE: usize = 100;
+/// Nombre maximal de groupes verifies lors d'une execution.
+const CONST_GROUPES_PAR_EXECUTION: i64 = 1_000;
+/// Delai minimal entre deux verifications de la cle d'un groupe.
+const CONST_DELAI_VERIFICATION_HEURES: i64 = 24;
+
+#[derive(Deserialize)]
+struct GroupeCle {
+    groupe_id: String,
+    user_id: String,
+    cle_id: Option<String>,
+    ref_hachage_bytes: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CleRetournee {
+    cle_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReponseDechiffrage {
+    ok: Option<bool>,
+    cles: Option<Vec<CleRetournee>>,
+}
+
+/// Verifie la cle des groupes qui n'ont pas ete verifies recemment.
+pub async fn verifier_cles_groupes<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let date_limite = Utc::now() - Duration::hours(CONST_DELAI_VERIFICATION_HEURES);
+    let filtre = doc! {
+        "$or": [
+            {"cle_verification_date": {"$exists": false}},
+            {"cle_verification_date": {"$lt": date_limite}},
+        ]
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {"groupe_id": 1, "user_id": 1, "cle_id": 1, "ref_hachage_bytes": 1})
+        .limit(CONST_GROUPES_PAR_EXECUTION)
+        .build();
+
+    // Regrouper les groupes par cle
+    let collection = middleware.get_collection_typed::<GroupeCle>(NOM_COLLECTION_GROUPES_USAGERS)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut groupes_par_cle: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    let mut groupes_sans_cle = Vec::new();
+    while let Some(row) = curseur.next().await {
+        let groupe = row?;
+        match groupe.cle_id.or(groupe.ref_hachage_bytes) {
+            Some(cle_id) => groupes_par_cle.entry(cle_id).or_default().push((groupe.user_id, groupe.groupe_id)),
+            None => groupes_sans_cle.push((groupe.user_id, groupe.groupe_id)),
+        }
+    }
+
+    let cle_ids: Vec<String> = groupes_par_cle.keys().cloned().collect();
+    let mut nombre_inconnues = 0;
+    for batch in cle_ids.chunks(CONST_CLES_PAR_REQUETE) {
+        let connues = match requete_cles_connues(middleware, batch).await? {
+            Some(inner) => inner,
+            None => {
+                // Pas de reponse de MaitreDesCles, reessayer a la prochaine execution
+                warn!("verifier_cles_groupes Aucune reponse de MaitreDesCles, verification interrompue");
+                return Ok(())
+            }
+        };
+        for cle_id in batch {
+            let inconnue = !connues.contains(cle_id);
+            if inconnue {
+                nombre_inconnues += 1;
+            }
+            if let Some(groupes) = groupes_par_cle.get(cle_id) {
+                for (user_id, groupe_id) in groupes {
+                    marquer_groupe(middleware, user_id, groupe_id, inconnue).await?;
+                }
+            }
+        }
+    }
+
+    for (user_id, groupe_id) in groupes_sans_cle {
+        marquer_groupe(middleware, &user_id, &groupe_id, true).await?;
+    }
+
+    info!("verifier_cles_groupes {} cles verifiees, {} inconnues", cle_ids.len(), nombre_inconnues);
+    Ok(())
+}
+
+/// Demande les cles a MaitreDesCles. Retourne les cle_ids connus, None si aucune reponse.
+async fn requete_cles_connues<M>(middleware: &M, cle_ids: &[String]) -> Result<Option<HashSet<String>>, Error>
+    where M: GenerateurMessages
+{
+    let routage = RoutageMessageAction::builder(
+        DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2, vec![Securite::L3Protege]
+    ).build();
+
+    let requete = RequeteDechiffrage {
+        domaine: DOMAINE_NOM.to_string(),
+        liste_hachage_bytes: None,
+        cle_ids: Some(cle_ids.to_vec()),
+        certificat_rechiffrage: None,
+        inclure_signature: None,
+    };
+
+    match middleware.transmettre_requete(routage, &requete).await? {
+        Some(TypeMessage::Valide(reponse)) => {
+            let message_ref = reponse.message.parse()?;
+            let reponse: ReponseDechiffrage = message_ref.contenu()?.deserialize()?;
+            debug!("requete_cles_connues Reponse ok : {:?}", reponse.ok);
+            if reponse.ok == Some(false) && reponse.cles.is_none() {
+                // Refus sans liste de cles (e.g. acces refuse). Ne pas marquer les groupes sur
+                // une reponse ambigue.
+                warn!("requete_cles_connues Reponse en erreur de MaitreDesCles");
+                return Ok(None)
+            }
+            let connues = reponse.cles.unwrap_or_default().into_iter()
+                .filter_map(|c| c.cle_id)
+                .collect();
+            Ok(Some(connues))
+        },
+        _ => Ok(None)
+    }
+}
+
+async fn marquer_groupe<M>(middleware: &M, user_id: &str, groupe_id: &str, cle_inconnue: bool) -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! { "user_id": user_id, "groupe_id": groupe_id };
+    let ops = match cle_inconnue {
+        true => doc! {
+            "$set": {"cle_inconnue": true},
+            "$currentDate": {"cle_verification_date": true},
+        },
+        false => doc! {
+            "$unset": {"cle_inconnue": true},
+            "$currentDate": {"cle_verification_date": true},
+        },
+    };
+    let collection = middleware.get_collection(NOM_COLLECTION_GROUPES_USAGERS)?;
+    collection.update_one(filtre, ops, None).await?;
+    Ok(())
+}